@@ -0,0 +1,300 @@
+use std::collections::HashMap;
+use raylib::prelude::*;
+use serde::{Serialize, Deserialize};
+use crate::rhythm::{Conductor, Sec};
+use crate::synth::Synth;
+use crate::{Level, RCError};
+
+/// The song a level is played to
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LevelMusic {
+    /// OGG or WAV file, relative to where the game is run from
+    pub path: String,
+    /// Seconds into the file where beat 0 of the level falls
+    #[serde(default)]
+    pub offset: Sec,
+}
+
+/// Which way a tile has to flip for its sound cue to play
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CueEdge {
+    /// When it appears
+    On,
+    /// When it vanishes
+    Off,
+    #[default]
+    Both,
+}
+
+/// A sound a rhythmic tile makes when it turns on or off
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SoundCue {
+    /// Sound file to play, relative to where the game is run from
+    pub sample: String,
+    #[serde(default = "default_level")]
+    pub pitch: f32,
+    #[serde(default = "default_level")]
+    pub volume: f32,
+    #[serde(default)]
+    pub edge: CueEdge,
+}
+
+fn default_level() -> f32 {
+    1.0
+}
+
+impl SoundCue {
+    /// Whether the cue plays when its tile turns `on` (or off)
+    pub fn plays_on(&self, on: bool) -> bool {
+        match self.edge {
+            CueEdge::On => on,
+            CueEdge::Off => !on,
+            CueEdge::Both => true
+        }
+    }
+}
+
+/// Somewhere to play a level's music, which can report how far into it playback is
+pub trait AudioBackend {
+    /// Start `path` from the beginning, replacing anything already playing
+    fn play_music(&mut self, path: &str) -> Result<(), RCError>;
+
+    fn stop_music(&mut self);
+
+    /// Called once a frame with the time since the last one, to keep streams fed
+    fn update(&mut self, delta: Sec);
+
+    /// Seconds of music played so far, or `None` if nothing is playing
+    fn music_time(&self) -> Option<Sec>;
+
+    /// Stream `synth` from the start of the level, replacing any synth already playing
+    fn play_synth(&mut self, synth: Synth);
+
+    fn stop_synth(&mut self);
+
+    /// Play a one-off sound over everything else
+    fn play_cue(&mut self, cue: &SoundCue);
+}
+
+/// Start a level's soundtrack from the top: its music if it has some,
+/// otherwise a synth playing its rhythms
+pub fn start_soundtrack(audio: &mut dyn AudioBackend, level: &Level) -> Result<(), RCError> {
+    match &level.music {
+        Some(music) => {
+            audio.stop_synth();
+            audio.play_music(&music.path)
+        },
+        None => {
+            audio.stop_music();
+            audio.play_synth(Synth::from_level(level));
+            Ok(())
+        }
+    }
+}
+
+/// Advance `clock` by a frame, following the music stream if the level has one
+pub fn follow_music(clock: &mut Conductor, audio: &dyn AudioBackend, music: Option<&LevelMusic>, delta: Sec) {
+    clock.advance(delta);
+    if let (Some(music), Some(time)) = (music, audio.music_time()) {
+        clock.sync(time - music.offset);
+    }
+}
+
+/// Plays nothing, but keeps time as if it were. For running without a sound device
+#[derive(Debug, Default)]
+pub struct NullAudio {
+    music: Option<String>,
+    synth: Option<Synth>,
+    cues: Vec<SoundCue>,
+    time: Sec,
+}
+
+impl NullAudio {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// What would be playing right now
+    pub fn music(&self) -> Option<&str> {
+        self.music.as_deref()
+    }
+
+    pub fn synth(&self) -> Option<&Synth> {
+        self.synth.as_ref()
+    }
+
+    /// Every cue played so far, oldest first
+    pub fn cues(&self) -> &[SoundCue] {
+        &self.cues
+    }
+
+    /// Pretend playback has reached `time`, e.g. to simulate a stream running ahead
+    pub fn set_time(&mut self, time: Sec) {
+        self.time = time;
+    }
+}
+
+impl AudioBackend for NullAudio {
+    fn play_music(&mut self, path: &str) -> Result<(), RCError> {
+        self.music = Some(path.to_string());
+        self.time = 0.0;
+        Ok(())
+    }
+
+    fn stop_music(&mut self) {
+        self.music = None;
+    }
+
+    fn update(&mut self, delta: Sec) {
+        if self.music.is_some() {
+            self.time += delta;
+        }
+    }
+
+    fn music_time(&self) -> Option<Sec> {
+        self.music.as_ref().map(|_| self.time)
+    }
+
+    fn play_synth(&mut self, synth: Synth) {
+        self.synth = Some(synth);
+    }
+
+    fn stop_synth(&mut self) {
+        self.synth = None;
+    }
+
+    fn play_cue(&mut self, cue: &SoundCue) {
+        self.cues.push(cue.clone());
+    }
+}
+
+/// A synth being fed to an audio stream a chunk at a time
+struct SynthStream {
+    synth: Synth,
+    stream: AudioStream,
+    /// next sample to render
+    position: u64,
+}
+
+/// Plays music through raylib's audio device
+pub struct RaylibBackend {
+    device: RaylibAudio,
+    thread: RaylibThread,
+    music: Option<Music>,
+    synth: Option<SynthStream>,
+    /// Cue samples, loaded the first time they're played
+    samples: HashMap<String, Sound>,
+}
+
+impl RaylibBackend {
+    pub fn new(thread: &RaylibThread) -> Self {
+        Self {
+            device: RaylibAudio::init_audio_device(),
+            thread: thread.clone(),
+            music: None,
+            synth: None,
+            samples: HashMap::new(),
+        }
+    }
+
+    /// Samples handed to the stream at a time; raylib's default stream buffer size
+    const STREAM_CHUNK: usize = 4096;
+}
+
+impl AudioBackend for RaylibBackend {
+    fn play_music(&mut self, path: &str) -> Result<(), RCError> {
+        self.stop_music();
+        let mut music = Music::load_music_stream(&self.thread, path).map_err(RCError::Audio)?;
+        music.looping = false;
+        self.device.play_music_stream(&mut music);
+        self.music = Some(music);
+        Ok(())
+    }
+
+    fn stop_music(&mut self) {
+        if let Some(mut music) = self.music.take() {
+            self.device.stop_music_stream(&mut music);
+        }
+    }
+
+    fn update(&mut self, _delta: Sec) {
+        if let Some(music) = &mut self.music {
+            self.device.update_music_stream(music);
+        }
+        if let Some(synth) = &mut self.synth {
+            while self.device.is_audio_stream_processed(&synth.stream) {
+                let mut chunk = vec![0.0; RaylibBackend::STREAM_CHUNK];
+                synth.synth.render(synth.position, &mut chunk);
+                synth.position += chunk.len() as u64;
+                // 8-bit unsigned: raylib's wrapper passes the stream a byte count
+                // where it wants a sample count, and for bytes those agree
+                let bytes: Vec<u8> = chunk.iter()
+                    .map(|s| (s.clamp(-1.0, 1.0) * 127.0 + 128.0) as u8)
+                    .collect();
+                synth.stream.update_audio_stream(&bytes);
+            }
+        }
+    }
+
+    fn music_time(&self) -> Option<Sec> {
+        self.music.as_ref()
+            .filter(|music| self.device.is_music_playing(music))
+            .map(|music| self.device.get_music_time_played(music) as Sec)
+    }
+
+    fn play_synth(&mut self, synth: Synth) {
+        self.stop_synth();
+        let mut stream = AudioStream::init_audio_stream(&self.thread, synth.sample_rate, 8, 1);
+        self.device.play_audio_stream(&mut stream);
+        self.synth = Some(SynthStream { synth, stream, position: 0 });
+    }
+
+    fn stop_synth(&mut self) {
+        if let Some(mut synth) = self.synth.take() {
+            self.device.stop_audio_stream(&mut synth.stream);
+        }
+    }
+
+    fn play_cue(&mut self, cue: &SoundCue) {
+        if !self.samples.contains_key(&cue.sample) {
+            match Sound::load_sound(&cue.sample) {
+                Ok(sound) => { self.samples.insert(cue.sample.clone(), sound); },
+                // a missing sample shouldn't stop the level
+                Err(_) => return
+            }
+        }
+        let sound = &self.samples[&cue.sample];
+        self.device.set_sound_pitch(sound, cue.pitch);
+        self.device.set_sound_volume(sound, cue.volume);
+        self.device.play_sound(sound);
+    }
+}
+
+#[test]
+fn clock_follows_music(){
+    let music = LevelMusic { path: "song.ogg".into(), offset: 0.5 };
+    let mut audio = NullAudio::new();
+    audio.play_music(&music.path).unwrap();
+    let mut clock = Conductor::new(120.0);
+    for _ in 0..60 {
+        audio.update(1.0 / 60.0);
+        follow_music(&mut clock, &audio, Some(&music), 1.0 / 60.0);
+    }
+    // a second of music, half of it before the first beat
+    assert!((clock.time() - 0.5).abs() < 1e-9);
+    assert!((clock.beat() - 1.0).abs() < 1e-9);
+
+    // the stream jumps ahead: a big gap snaps, a small one is eased out
+    audio.set_time(2.0);
+    follow_music(&mut clock, &audio, Some(&music), 0.0);
+    assert!((clock.time() - 1.5).abs() < 1e-9);
+    audio.set_time(2.02);
+    follow_music(&mut clock, &audio, Some(&music), 0.0);
+    assert!(clock.time() > 1.5 && clock.time() < 1.52);
+
+    // without music the clock runs on frame time alone
+    let mut free = Conductor::new(120.0);
+    follow_music(&mut free, &audio, None, 0.25);
+    assert!((free.time() - 0.25).abs() < 1e-9);
+}
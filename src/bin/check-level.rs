@@ -0,0 +1,37 @@
+use rhythm_chase::*;
+use rhythm_chase::format::{upgrade_file, FORMAT_VERSION};
+use rhythm_chase::solver::Solver;
+
+/// Check that a level is well formed and can be cleared, and show the quickest way how:
+///     check-level maps/begin.json
+/// With --upgrade, levels in an older format are rewritten in the current one first
+fn main() -> Result<(), RCError> {
+    let args: Vec<String> = std::env::args().collect();
+    let upgrade = args.iter().any(|a| a == "--upgrade");
+    let Some(path) = args.iter().skip(1).find(|a| *a != "--upgrade") else {
+        eprintln!("usage: {} [--upgrade] <level.json>", args[0]);
+        std::process::exit(2);
+    };
+    if upgrade {
+        let version = upgrade_file(path)?;
+        if version < FORMAT_VERSION {
+            println!("{}: upgraded from format {} to {}", path, version, FORMAT_VERSION);
+        }
+    }
+    let level = match Level::load(path) {
+        Ok(level) => level,
+        Err(e @ RCError::Validation(_)) => {
+            println!("{}: {}", path, e);
+            std::process::exit(1);
+        }
+        Err(e) => return Err(e),
+    };
+    match Solver::new(&level).solve() {
+        Some(solution) => print!("{}: {}", path, solution),
+        None => {
+            println!("{}: can't be cleared", path);
+            std::process::exit(1);
+        }
+    }
+    Ok(())
+}
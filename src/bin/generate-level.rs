@@ -0,0 +1,39 @@
+use std::str::FromStr;
+use rhythm_chase::*;
+use rhythm_chase::generator::{generate, GeneratorOptions};
+
+/// The value following `flag` on the command line, parsed, if it's there
+fn flag<T: FromStr>(args: &[String], flag: &str) -> Option<T> {
+    args.iter().position(|a| a == flag).and_then(|i| args.get(i + 1)).and_then(|v| v.parse().ok())
+}
+
+/// Generate a level that can be cleared, and write it out as JSON:
+///     generate-level [--rows 8] [--columns 10] [--tempo 120] [--difficulty 4]
+///                    [--seed 0] [--pattern x. ...] [--out level.json]
+fn main() -> Result<(), RCError> {
+    let args: Vec<String> = std::env::args().collect();
+    let defaults = GeneratorOptions::default();
+    // every --pattern adds a rhythm to the palette; without any, the defaults are used
+    let mut palette = vec![];
+    for (i, arg) in args.iter().enumerate() {
+        if arg == "--pattern" {
+            if let Some(pattern) = args.get(i + 1) {
+                palette.push(pattern.parse()?);
+            }
+        }
+    }
+    let options = GeneratorOptions {
+        rows: flag(&args, "--rows").unwrap_or(defaults.rows),
+        columns: flag(&args, "--columns").unwrap_or(defaults.columns),
+        tempo: flag(&args, "--tempo").unwrap_or(defaults.tempo),
+        palette: if palette.is_empty() { defaults.palette } else { palette },
+        difficulty: flag(&args, "--difficulty").unwrap_or(defaults.difficulty),
+        seed: flag(&args, "--seed").unwrap_or(defaults.seed),
+    };
+    let level = generate(&options)?;
+    match flag::<String>(&args, "--out") {
+        Some(path) => serde_json::to_writer_pretty(std::fs::File::create(path)?, &level)?,
+        None => println!("{}", serde_json::to_string_pretty(&level)?)
+    }
+    Ok(())
+}
@@ -1,7 +1,18 @@
 
 use raylib::prelude::*;
-use std::{io, time::*};
 use rhythm_chase::*;
+use rhythm_chase::audio::RaylibBackend;
+use rhythm_chase::inputs::InputCollector;
+use rhythm_chase::calibration::{Calibration, Settings, Stage, SETTINGS_PATH};
+use rhythm_chase::replay::Replay;
+use rhythm_chase::view::{GameView, TileDimensions};
+
+
+/// The value following `flag` on the command line, if it's there
+fn flag_value(flag: &str) -> Option<String> {
+    let args: Vec<String> = std::env::args().collect();
+    args.iter().position(|a| a == flag).and_then(|i| args.get(i + 1).cloned())
+}
 
 
 
@@ -39,19 +50,57 @@ fn main() -> Result<(), rhythm_chase::RCError>{
         rotation: 0.0,
         zoom: 1.0
     };
-    let mut game = Game::new(camera, dimensions);
-    game.load_level("maps/begin.json")?;
-    let mut time = SystemTime::now();
+    let mut audio = RaylibBackend::new(&thread);
+    let mut input = InputCollector::new();
+    if std::env::args().any(|a| a == "--calibrate") {
+        let mut calibration = Calibration::new(&mut audio);
+        while !rl.window_should_close() && calibration.stage() != Stage::Done {
+            let delta = rl.get_frame_time() as f64;
+            let inputs = input.collect(&mut rl, calibration.time() + delta);
+            calibration.update(delta, &inputs, &mut audio);
+            let mut d: RaylibDrawHandle = rl.begin_drawing(&thread);
+            d.clear_background(Color::WHITE);
+            calibration.draw(&mut d);
+        }
+        if let Some(settings) = calibration.settings() {
+            settings.save(SETTINGS_PATH)?;
+        }
+    }
+
+    // --replay plays a recording back instead of taking input;
+    // --record saves this session's last attempt when the window closes
+    let replay = flag_value("--replay").map(|path| Replay::load(&path)).transpose()?;
+    let record = flag_value("--record");
+
+    let view = GameView::new(camera, dimensions);
+    let mut game = Game::new();
+    game.apply_settings(match &replay {
+        Some(replay) => replay.settings,
+        None => Settings::load(SETTINGS_PATH)?
+    });
+    game.set_audio(Box::new(audio));
+    game.load_level(replay.as_ref().map_or("maps/begin.json", |r| r.level.as_str()))?;
+    if record.is_some() {
+        game.record(0);
+    }
     while !rl.window_should_close() {
-        let duration = SystemTime::now().duration_since(time).unwrap();
-        time = SystemTime::now();
-        let inputs = rhythm_chase::inputs::get_inputs(&mut rl);
-        game.update(duration.as_secs_f64(), &inputs);
+        // the game follows the music stream when the level has one;
+        // frame time only fills in between stream updates
+        let delta = rl.get_frame_time() as f64;
+        // presses are stamped against where the clock is about to be
+        let mut inputs = input.collect(&mut rl, game.time() + delta);
+        if let Some(replay) = &replay {
+            inputs = replay.inputs_between(game.time(), game.time() + delta);
+        }
+        game.update(delta, &inputs);
         {
             let mut d: RaylibDrawHandle = rl.begin_drawing(&thread);
             d.clear_background(Color::WHITE);
-            game.draw(&mut d);
+            view.draw(&game, &mut d);
         }
     }
+    if let (Some(path), Some(recording)) = (record, game.recording()) {
+        recording.save(&path)?;
+    }
     Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,24 @@
+use rhythm_chase::*;
+use rhythm_chase::difficulty::Difficulty;
+
+/// Rate how hard levels are, easiest first, as JSON:
+///     rate-levels maps/*.json
+fn main() -> Result<(), RCError> {
+    let paths: Vec<String> = std::env::args().skip(1).collect();
+    if paths.is_empty() {
+        eprintln!("usage: rate-levels <level.json>...");
+        std::process::exit(2);
+    }
+    let mut rated = vec![];
+    for path in paths {
+        let level = Level::load(&path)?;
+        rated.push((path, Difficulty::of(&level)));
+    }
+    // levels that can't be cleared go last
+    rated.sort_by(|(_, a), (_, b)| a.score.unwrap_or(f64::INFINITY).total_cmp(&b.score.unwrap_or(f64::INFINITY)));
+    let report: Vec<serde_json::Value> = rated.into_iter()
+        .map(|(path, difficulty)| serde_json::json!({ "level": path, "difficulty": difficulty }))
+        .collect();
+    println!("{}", serde_json::to_string_pretty(&report)?);
+    Ok(())
+}
@@ -0,0 +1,19 @@
+use std::io::BufWriter;
+use rhythm_chase::*;
+use rhythm_chase::synth::{Synth, write_wav};
+
+/// Render the synth soundtrack of a level to a WAV file:
+///     render-beats maps/begin.json begin.wav [seconds]
+fn main() -> Result<(), RCError> {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() < 3 {
+        eprintln!("usage: {} <level.json> <out.wav> [seconds]", args[0]);
+        std::process::exit(2);
+    }
+    let seconds: f64 = args.get(3).and_then(|s| s.parse().ok()).unwrap_or(16.0);
+    let level = Level::load(&args[1])?;
+    let synth = Synth::from_level(&level);
+    let samples = synth.render_seconds(seconds);
+    write_wav(&mut BufWriter::new(std::fs::File::create(&args[2])?), &samples, synth.sample_rate)?;
+    Ok(())
+}
@@ -0,0 +1,185 @@
+use std::io::BufReader;
+use raylib::prelude::*;
+use serde::{Serialize, Deserialize};
+use crate::audio::AudioBackend;
+use crate::inputs::Input;
+use crate::rhythm::{Conductor, Rhythm, Sec, BPM};
+use crate::synth::{Synth, Track, Voice};
+use crate::RCError;
+
+/// Where settings are kept, relative to where the game is run from
+pub const SETTINGS_PATH: &str = "settings.json";
+
+/// Player settings that survive between runs
+#[derive(Debug, Default, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct Settings {
+    /// How late the player's presses land after the beat they hear, in seconds
+    #[serde(default)]
+    pub input_offset: Sec,
+    /// How much later the screen shows a beat than the speakers play it, in seconds
+    #[serde(default)]
+    pub visual_offset: Sec,
+}
+
+impl Settings {
+    /// Read settings from `path`, falling back to no offsets if there aren't any yet
+    pub fn load(path: &str) -> Result<Settings, RCError> {
+        match std::fs::File::open(path) {
+            Ok(file) => Ok(serde_json::from_reader(BufReader::new(file))?),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(Settings::default()),
+            Err(e) => Err(e.into())
+        }
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), RCError> {
+        serde_json::to_writer_pretty(std::fs::File::create(path)?, self)?;
+        Ok(())
+    }
+}
+
+/// What the calibration screen is currently asking for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stage {
+    /// Tap along to a click you can hear
+    Audio,
+    /// Tap along to a square you can see
+    Visual,
+    Done,
+}
+
+/// A screen where the player taps along to a click track and then a flashing
+/// square, to measure how late their input and the screen are
+pub struct Calibration {
+    clock: Conductor,
+    stage: Stage,
+    /// seconds from the nearest beat to each tap in this stage
+    taps: Vec<Sec>,
+    /// mean tap offset from the audio stage
+    input_offset: Sec,
+    settings: Option<Settings>,
+}
+
+impl Calibration {
+    const TEMPO: BPM = 100.0;
+    /// Taps to take in each stage
+    const TAPS: usize = 16;
+    /// Taps at the start of a stage that don't count, while the player finds the beat
+    const WARMUP: usize = 4;
+
+    pub fn new(audio: &mut dyn AudioBackend) -> Self {
+        let mut calibration = Calibration {
+            clock: Conductor::new(Calibration::TEMPO),
+            stage: Stage::Audio,
+            taps: vec![],
+            input_offset: 0.0,
+            settings: None,
+        };
+        calibration.start_stage(Stage::Audio, audio);
+        calibration
+    }
+
+    fn start_stage(&mut self, stage: Stage, audio: &mut dyn AudioBackend) {
+        self.stage = stage;
+        self.taps.clear();
+        self.clock.reset();
+        audio.stop_music();
+        match stage {
+            Stage::Audio => {
+                let mut click = Synth::new(Calibration::TEMPO.into());
+                click.tracks.push(Track { rhythm: Rhythm::new(1, [0]), voice: Voice::Kick, gain: 1.0 });
+                click.crush = None;
+                audio.play_synth(click);
+            },
+            Stage::Visual | Stage::Done => audio.stop_synth()
+        }
+    }
+
+    /// Seconds on the calibration clock, which starts over each stage
+    pub fn time(&self) -> Sec {
+        self.clock.time()
+    }
+
+    pub fn stage(&self) -> Stage {
+        self.stage
+    }
+
+    /// The measured settings, once both stages are done
+    pub fn settings(&self) -> Option<Settings> {
+        self.settings
+    }
+
+    /// Mean offset of the taps so far, ignoring the warmup
+    fn mean_offset(&self) -> Sec {
+        let counted = &self.taps[Calibration::WARMUP.min(self.taps.len())..];
+        counted.iter().sum::<Sec>() / counted.len().max(1) as Sec
+    }
+
+    pub fn update(&mut self, delta: Sec, inputs: &[Input], audio: &mut dyn AudioBackend) {
+        audio.update(delta);
+        self.clock.advance(delta);
+        if self.stage == Stage::Done {
+            return;
+        }
+        for input in inputs {
+            if let Input::Key(KeyboardKey::KEY_SPACE, time) = input {
+                let tempo = self.clock.tempo();
+                let nearest = tempo.seconds_at(tempo.beat_at(*time).round());
+                self.taps.push(time - nearest);
+            }
+        }
+        if self.taps.len() >= Calibration::TAPS {
+            match self.stage {
+                Stage::Audio => {
+                    self.input_offset = self.mean_offset();
+                    self.start_stage(Stage::Visual, audio);
+                },
+                Stage::Visual => {
+                    self.settings = Some(Settings {
+                        input_offset: self.input_offset,
+                        visual_offset: self.mean_offset() - self.input_offset,
+                    });
+                    self.start_stage(Stage::Done, audio);
+                },
+                Stage::Done => {}
+            }
+        }
+    }
+
+    pub fn draw(&self, handle: &mut RaylibDrawHandle) {
+        let message = match self.stage {
+            Stage::Audio => "Tap SPACE along with the beat you hear",
+            Stage::Visual => "Tap SPACE along with the square",
+            Stage::Done => "All done! Saved.",
+        };
+        handle.draw_text(message, 40, 40, 20, Color::BLACK);
+        if self.stage != Stage::Done {
+            let progress = format!("{} / {}", self.taps.len(), Calibration::TAPS);
+            handle.draw_text(&progress, 40, 70, 18, Color::DARKGRAY);
+        }
+        if self.stage == Stage::Visual && self.clock.beat().fract() < 0.15 {
+            handle.draw_rectangle(270, 190, 100, 100, Color::BLACK);
+        }
+    }
+}
+
+#[test]
+fn measures_offsets(){
+    let mut audio = crate::audio::NullAudio::new();
+    let mut calibration = Calibration::new(&mut audio);
+    let beat = 60.0 / Calibration::TEMPO;
+    // the player hears the click 30ms late and sees the square 50ms late
+    for (stage, late) in [(Stage::Audio, 0.03), (Stage::Visual, 0.05)] {
+        assert_eq!(calibration.stage(), stage);
+        assert!(audio.synth().is_some() == (stage == Stage::Audio));
+        // each stage starts its clock over, so tap `late` after each of its beats
+        for i in 0..Calibration::TAPS {
+            let delta = if i == 0 { beat + late } else { beat };
+            let tap = Input::Key(KeyboardKey::KEY_SPACE, calibration.time() + delta);
+            calibration.update(delta, &[tap], &mut audio);
+        }
+    }
+    assert_eq!(calibration.stage(), Stage::Done);
+    let settings = calibration.settings().unwrap();
+    assert!((settings.input_offset - 0.03).abs() < 1e-9);
+    assert!((settings.visual_offset - 0.02).abs() < 1e-9);
+}
@@ -0,0 +1,218 @@
+use std::collections::{HashMap, VecDeque};
+use serde::{Serialize, Deserialize};
+use crate::rhythm::{Beat, Sec};
+use crate::solver::{Outcome, Solution, Solver, State};
+use crate::state::LevelState;
+use crate::tiles::PlacedTile;
+use crate::{forced_move, step_on, Level};
+
+/// How hard a level is, and what goes into that
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Difficulty {
+    /// Higher is harder. Levels that can't be cleared don't get one
+    pub score: Option<f64>,
+    pub clearable: bool,
+    /// Beat the goal can be reached on at the earliest
+    pub min_beats: Option<usize>,
+    /// Ways to clear the level in no more than `min_beats` plus one cycle of its rhythms
+    pub winning_paths: f64,
+    /// Fewest beats that have to be spent standing still, by any route
+    pub min_waits: Option<usize>,
+    /// On the quickest route, the least time in seconds a rhythmic tile is there
+    /// either side of when the player needs it, capped at a beat
+    pub tightest_window: Option<Sec>,
+    /// Beats the quickest route spends standing on tiles that come and go
+    pub beats_on_rhythmic_tiles: usize,
+    /// Fraction of the time rhythmic cells are there, averaged over them
+    pub rhythm_density: f64,
+}
+
+impl Difficulty {
+    pub fn of(level: &Level) -> Difficulty {
+        let mut solver = Solver::new(level);
+        let solution = solver.solve();
+        let density = rhythm_density(level);
+        let Some(solution) = solution else {
+            return Difficulty {
+                score: None, clearable: false, min_beats: None, winning_paths: 0.0, min_waits: None,
+                tightest_window: None, beats_on_rhythmic_tiles: 0, rhythm_density: density,
+            };
+        };
+        let horizon = solution.beats + solver.period();
+        let paths = count_paths(&mut solver, horizon);
+        let waits = min_waits(&mut solver);
+        let route = route(level, &solution);
+        let on_rhythmic = route.iter()
+            .filter(|(p, _)| level.tiles.get(p.0, p.1).map(|t| t.rhythm.is_some()).unwrap_or(false))
+            .count();
+        let tightest = tightest_window(level, &solver, &route);
+
+        let mut score = (1.0 + solution.beats as f64).ln();
+        score += 1.5 * waits.unwrap_or(0) as f64;
+        score += 2.0 / (1.0 + paths.max(1.0).log10());
+        score += 0.25 * on_rhythmic as f64;
+        score += 2.0 * (1.0 - density);
+        if let Some(tightest) = tightest {
+            let beat = level.tempo.beat_length_at(0.0);
+            score += 3.0 * (1.0 - tightest / beat).max(0.0);
+        }
+        Difficulty {
+            score: Some((score * 100.0).round() / 100.0),
+            clearable: true,
+            min_beats: Some(solution.beats),
+            winning_paths: paths,
+            min_waits: waits,
+            tightest_window: tightest,
+            beats_on_rhythmic_tiles: on_rhythmic,
+            rhythm_density: density,
+        }
+    }
+}
+
+/// Winning move sequences that reach the goal on or before `horizon`
+fn count_paths(solver: &mut Solver, horizon: usize) -> f64 {
+    let mut layer: HashMap<State, f64> = HashMap::from([(solver.start(), 1.0)]);
+    let mut wins = 0.0;
+    for _ in 0..=horizon {
+        let mut next: HashMap<State, f64> = HashMap::new();
+        for (state, ways) in layer {
+            for (_, outcome) in solver.moves(state) {
+                match outcome {
+                    Outcome::Cleared => wins += ways,
+                    Outcome::Alive(to) => *next.entry(to).or_default() += ways
+                }
+            }
+        }
+        layer = next;
+    }
+    wins
+}
+
+/// Fewest waits on any way to the goal, counting moves as free
+fn min_waits(solver: &mut Solver) -> Option<usize> {
+    let start = solver.start();
+    let mut best = HashMap::from([(solver.key(start), 0)]);
+    let mut queue = VecDeque::from([(start, 0)]);
+    let mut found: Option<usize> = None;
+    while let Some((state, waits)) = queue.pop_front() {
+        if best.get(&solver.key(state)).is_some_and(|b| *b < waits) {
+            continue;
+        }
+        for (direction, outcome) in solver.moves(state) {
+            let cost = waits + direction.is_none() as usize;
+            match outcome {
+                Outcome::Cleared => found = Some(found.map_or(cost, |f| f.min(cost))),
+                Outcome::Alive(next) => {
+                    let key = solver.key(next);
+                    if best.get(&key).is_none_or(|b| cost < *b) {
+                        best.insert(key, cost);
+                        // waits go to the back, so states come off in order of cost
+                        if direction.is_none() { queue.push_back((next, cost)) } else { queue.push_front((next, cost)) }
+                    }
+                }
+            }
+        }
+    }
+    found
+}
+
+/// Where the player stands through each beat of `solution`, up to the goal,
+/// and the tile there as things stood
+fn route<'a>(level: &'a Level, solution: &Solution) -> Vec<((usize, usize), PlacedTile<'a>)> {
+    let (mut position, mut heading) = (level.starting_location, None);
+    let mut state = LevelState::default();
+    let mut moves = solution.moves.iter().peekable();
+    (0..solution.beats).map(|beat| {
+        if let Some((_, direction)) = moves.next_if(|(b, _)| *b == beat) {
+            state.wear(&level.tiles, None);
+            let to = step_on(&level.tiles, &state, position, direction.offset());
+            if to != position {
+                heading = Some(direction.offset());
+                position = state.land(&level.tiles, to, direction.offset(), beat as Beat);
+            }
+        } else {
+            state.wear(&level.tiles, Some(position));
+            (position, heading) = forced_move(&level.tiles, &mut state, position, heading, beat as Beat);
+        }
+        (position, state.get(&level.tiles, position.0, position.1).expect("moves stay on the map"))
+    }).collect()
+}
+
+/// The least time a rhythmic tile on `route` is there before the player steps on or after they step off
+fn tightest_window(level: &Level, solver: &Solver, route: &[((usize, usize), PlacedTile)]) -> Option<Sec> {
+    let step = 1.0 / Solver::SAMPLES as Beat;
+    // how long `tile` is there going from `beat` in `direction` steps, up to a beat
+    let margin = |tile: &PlacedTile, beat: Beat, direction: Beat| {
+        let inside = (1..=Solver::SAMPLES)
+            .take_while(|i| solver.standable(tile, beat + direction * *i as Beat * step))
+            .count();
+        let beats = inside as Beat * step;
+        level.tempo.beat_length_at(beat) * beats
+    };
+    let mut tightest: Option<Sec> = None;
+    for (beat, (position, tile)) in route.iter().enumerate() {
+        if tile.rhythm.is_none() {
+            continue;
+        }
+        let arrived = beat == 0 || route[beat - 1].0 != *position;
+        let leaving = beat + 1 < route.len() && route[beat + 1].0 != *position;
+        let mut windows = vec![];
+        if arrived {
+            windows.push(margin(tile, beat as Beat, -1.0));
+        }
+        if leaving || beat + 1 == route.len() {
+            windows.push(margin(tile, (beat + 1) as Beat, 1.0));
+        }
+        for window in windows {
+            tightest = Some(tightest.map_or(window, |t: Sec| t.min(window)));
+        }
+    }
+    tightest
+}
+
+/// Fraction of the time rhythmic cells are there, averaged over them; 1 if there are none
+fn rhythm_density(level: &Level) -> f64 {
+    let densities: Vec<f64> = level.tiles.enumerate_column_major()
+        .filter_map(|(_, t)| t.tile.rhythm.as_ref())
+        .map(|r| r.beats.len() as f64 / r.ticks().max(1) as f64)
+        .collect();
+    if densities.is_empty() {
+        return 1.0;
+    }
+    densities.iter().sum::<f64>() / densities.len() as f64
+}
+
+#[test]
+fn rates_levels(){
+    let begin: Level = serde_json::from_reader(std::fs::File::open("maps/begin.json").unwrap()).unwrap();
+    let difficulty = Difficulty::of(&begin);
+    assert!(difficulty.clearable);
+    assert_eq!(difficulty.min_beats, Some(5));
+    assert_eq!(difficulty.min_waits, Some(0));
+    // the straight route right never touches the rhythmic column
+    assert_eq!(difficulty.beats_on_rhythmic_tiles, 0);
+    assert_eq!(difficulty.tightest_window, None);
+    assert!(difficulty.winning_paths > 1.0);
+    assert!((difficulty.rhythm_density - 0.5).abs() < 1e-9);
+
+    let big: Level = serde_json::from_reader(std::fs::File::open("maps/bigmap.json").unwrap()).unwrap();
+    let difficulty = Difficulty::of(&big);
+    assert!(!difficulty.clearable);
+    assert_eq!(difficulty.score, None);
+    let json = serde_json::to_value(&difficulty).unwrap();
+    assert_eq!(json["clearable"], false);
+}
+
+#[test]
+fn timing_makes_levels_harder(){
+    use crate::tiles::{column, floor, goal, Tile};
+    use crate::rhythm::Rhythm;
+    let blinking = Tile { rhythm: Some(Rhythm::new(2, [1])), ..floor() };
+    let easy = column([floor(), floor(), goal()]);
+    let hard = column([floor(), blinking, goal()]);
+    let (easy, hard) = (Difficulty::of(&easy), Difficulty::of(&hard));
+    assert_eq!(hard.min_waits, Some(1));
+    assert_eq!(hard.beats_on_rhythmic_tiles, 1);
+    assert!(hard.tightest_window.unwrap() < 0.5);
+    assert!(hard.score > easy.score);
+}
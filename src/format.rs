@@ -0,0 +1,95 @@
+use serde_json::Value;
+use crate::validation::Problem;
+use crate::RCError;
+
+/// Version of the level format this build reads and writes. Files from before
+/// versions were written down count as version 0
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Upgrades from each version to the next: `MIGRATIONS[0]` takes a level from 0 to 1
+const MIGRATIONS: [fn(&mut serde_json::Map<String, Value>); FORMAT_VERSION as usize] = [
+    drop_unused_fields,
+];
+
+/// Upgrade level JSON to `FORMAT_VERSION`, one version at a time.
+/// Returns the version the level was written in
+pub fn migrate(level: &mut Value) -> Result<u32, RCError> {
+    let Some(fields) = level.as_object_mut() else {
+        return Err(RCError::Validation(vec![Problem::new("$", "a level has to be a JSON object")]));
+    };
+    let version = match fields.get("format_version") {
+        None => 0,
+        Some(v) => match v.as_u64() {
+            Some(v) if v <= FORMAT_VERSION as u64 => v as u32,
+            Some(v) => return Err(RCError::Validation(vec![Problem::new("$.format_version",
+                format!("version {} is newer than this game, which reads up to {}", v, FORMAT_VERSION))])),
+            None => return Err(RCError::Validation(vec![Problem::new("$.format_version",
+                "the format version has to be a whole number")])),
+        }
+    };
+    for migration in &MIGRATIONS[version as usize..] {
+        migration(fields);
+    }
+    fields.insert("format_version".to_string(), FORMAT_VERSION.into());
+    Ok(version)
+}
+
+/// Upgrade the level file at `path` to the current format, writing it back in place.
+/// Returns the version it was in; files that are already current aren't touched
+pub fn upgrade_file(path: &str) -> Result<u32, RCError> {
+    let mut level: Value = serde_json::from_reader(std::io::BufReader::new(std::fs::File::open(path)?))?;
+    let version = migrate(&mut level)?;
+    if version < FORMAT_VERSION {
+        // same indent as the hand-written maps
+        let mut out = serde_json::Serializer::with_formatter(
+            std::fs::File::create(path)?, serde_json::ser::PrettyFormatter::with_indent(b"    "));
+        serde::Serialize::serialize(&level, &mut out)?;
+    }
+    Ok(version)
+}
+
+/// 0 to 1: `dimensions` belongs to the view, not the level, and nothing ever
+/// read the `duration` rhythms were saved with
+fn drop_unused_fields(level: &mut serde_json::Map<String, Value>) {
+    level.remove("dimensions");
+    if let Some(Value::Array(tiles)) = level.get_mut("tiles") {
+        for tile in tiles {
+            if let Some(rhythm) = tile.get_mut("rhythm") {
+                drop_durations(rhythm);
+            }
+        }
+    }
+}
+
+/// Take `duration` out of a rhythm and any it's combined from
+fn drop_durations(rhythm: &mut Value) {
+    match rhythm {
+        Value::Object(fields) => {
+            fields.remove("duration");
+            fields.values_mut().for_each(drop_durations);
+        },
+        Value::Array(parts) => parts.iter_mut().for_each(drop_durations),
+        _ => {}
+    }
+}
+
+#[test]
+fn old_levels_are_upgraded(){
+    use crate::Level;
+    let mut begin: Value = serde_json::from_reader(std::fs::File::open("maps/begin.json").unwrap()).unwrap();
+    assert!(begin.get("dimensions").is_some());
+    assert_eq!(migrate(&mut begin).unwrap(), 0);
+    assert_eq!(begin["format_version"], FORMAT_VERSION);
+    assert!(begin.get("dimensions").is_none());
+    assert!(begin["tiles"][1]["rhythm"].get("duration").is_none());
+    // upgrading twice changes nothing
+    let upgraded = begin.clone();
+    assert_eq!(migrate(&mut begin).unwrap(), FORMAT_VERSION);
+    assert_eq!(begin, upgraded);
+    // and the upgraded level plays the same as the original
+    let level: Level = serde_json::from_value(begin).unwrap();
+    assert_eq!(serde_json::to_value(&level).unwrap(), serde_json::to_value(Level::load("maps/begin.json").unwrap()).unwrap());
+
+    let mut future = serde_json::json!({ "format_version": FORMAT_VERSION + 1 });
+    assert!(matches!(migrate(&mut future), Err(RCError::Validation(_))));
+}
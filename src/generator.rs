@@ -0,0 +1,193 @@
+use array2d::Array2D;
+use raylib::prelude::Color;
+use crate::difficulty::Difficulty;
+use crate::rhythm::{Beat, Rhythm, BPM};
+use crate::solver::Solver;
+use crate::tiles::Tile;
+use crate::{Level, RCError};
+
+/// What kind of level to generate
+#[derive(Debug, Clone)]
+pub struct GeneratorOptions {
+    /// Cells along the player's left-right axis; at least 2
+    pub rows: usize,
+    pub columns: usize,
+    pub tempo: BPM,
+    /// Rhythms for the tiles that come and go
+    pub palette: Vec<Rhythm>,
+    /// Score to aim for, as rated by `Difficulty::of`
+    pub difficulty: f64,
+    pub seed: u64,
+}
+
+impl Default for GeneratorOptions {
+    fn default() -> Self {
+        GeneratorOptions {
+            rows: 8,
+            columns: 10,
+            tempo: 120.0,
+            palette: vec![Rhythm::new(2, [0]), Rhythm::new(2, [1]), Rhythm::euclid(3, 4, 0).expect("E(3, 4) is well inside the limits")],
+            difficulty: 4.0,
+            seed: 0,
+        }
+    }
+}
+
+/// splitmix64: small, and gives the same numbers on every platform
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+
+    /// A number from 0 up to but not including `n`
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n.max(1) as u64) as usize
+    }
+
+    /// A number from 0 to 1
+    fn unit(&mut self) -> f64 {
+        (self.next() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// The cells of a level being generated, before it's packed into a `TileMap`
+#[derive(Clone)]
+struct Draft {
+    /// which palette rhythm each cell plays, if any
+    cells: Array2D<Option<usize>>,
+    phase: Array2D<Beat>,
+    start: (usize, usize),
+    goal: (usize, usize),
+}
+
+const FLOOR: Color = Color { r: 60, g: 60, b: 60, a: 255 };
+const GOAL: Color = Color { r: 5, g: 225, b: 5, a: 255 };
+const COLORS: [Color; 5] = [
+    Color { r: 200, g: 200, b: 10, a: 255 },
+    Color { r: 5, g: 200, b: 200, a: 255 },
+    Color { r: 200, g: 5, b: 120, a: 255 },
+    Color { r: 230, g: 120, b: 10, a: 255 },
+    Color { r: 120, g: 60, b: 220, a: 255 },
+];
+
+impl Draft {
+    fn level(&self, options: &GeneratorOptions) -> Result<Level, RCError> {
+        let floor = Tile { color: FLOOR, ..Default::default() };
+        let goal = Tile { color: GOAL, goal: true, ..Default::default() };
+        let tiles = Array2D::from_iter_row_major(self.cells.enumerate_row_major().map(|((r, c), cell)| {
+            match cell {
+                _ if (r, c) == self.goal => goal.clone(),
+                Some(i) => Tile {
+                    color: COLORS[i % COLORS.len()],
+                    rhythm: Some(options.palette[*i].clone()),
+                    ..Default::default()
+                },
+                None => floor.clone(),
+            }
+        }), self.cells.num_rows(), self.cells.num_columns())?;
+        let mut level = Level::new(tiles, self.start, options.tempo);
+        for ((r, c), phase) in self.phase.enumerate_row_major() {
+            if *phase != 0.0 {
+                level.tiles.set_phase(r, c, *phase);
+            }
+        }
+        Ok(level)
+    }
+}
+
+/// Generate a level to `options`, whose goal can always be reached. The same
+/// options always give the same level
+pub fn generate(options: &GeneratorOptions) -> Result<Level, RCError> {
+    /// Layouts to try, keeping the one closest to the target difficulty
+    const ATTEMPTS: usize = 24;
+    let (rows, columns) = (options.rows.max(2), options.columns.max(1));
+    let mut rng = Rng(options.seed);
+    let mut best: Option<(f64, Level)> = None;
+    for _ in 0..ATTEMPTS {
+        let start = (0, rng.below(columns));
+        let goal = (rows - 1, rng.below(columns));
+        // harder levels get more tiles that come and go
+        let density = (0.15 + 0.08 * options.difficulty + 0.2 * (rng.unit() - 0.5)).clamp(0.0, 0.9);
+        let mut draft = Draft {
+            cells: Array2D::filled_with(None, rows, columns),
+            phase: Array2D::filled_with(0.0, rows, columns),
+            start,
+            goal,
+        };
+        if !options.palette.is_empty() {
+            for r in 0..rows {
+                for c in 0..columns {
+                    if (r, c) != start && (r, c) != goal && rng.unit() < density {
+                        let rhythm = rng.below(options.palette.len());
+                        draft.cells[(r, c)] = Some(rhythm);
+                        draft.phase[(r, c)] = rng.below(options.palette[rhythm].length) as Beat;
+                    }
+                }
+            }
+        }
+        // lay floor along a wandering path to the goal until there's a way through;
+        // with the whole path floored there always is
+        let path = wander(&mut rng, start, goal);
+        let mut level = draft.level(options)?;
+        for cell in path {
+            if Solver::new(&level).solve().is_some() {
+                break;
+            }
+            draft.cells[cell] = None;
+            draft.phase[cell] = 0.0;
+            level = draft.level(options)?;
+        }
+        let score = Difficulty::of(&level).score.unwrap_or(f64::INFINITY);
+        let miss = (score - options.difficulty).abs();
+        if best.as_ref().is_none_or(|(b, _)| miss < *b) {
+            best = Some((miss, level));
+        }
+    }
+    Ok(best.map(|(_, level)| level).expect("at least one attempt"))
+}
+
+/// A path of neighbouring cells from `start` to `goal`, in a random order of steps
+fn wander(rng: &mut Rng, start: (usize, usize), goal: (usize, usize)) -> Vec<(usize, usize)> {
+    let mut path = vec![];
+    let mut at = start;
+    while at != goal {
+        let across = at.1 != goal.1 && (at.0 == goal.0 || rng.unit() < 0.4);
+        if across {
+            at.1 = if goal.1 > at.1 { at.1 + 1 } else { at.1 - 1 };
+        } else {
+            at.0 += 1;
+        }
+        if at != goal {
+            path.push(at);
+        }
+    }
+    path
+}
+
+#[test]
+fn generated_levels_can_be_cleared(){
+    for seed in 0..4 {
+        let options = GeneratorOptions { seed, ..Default::default() };
+        let level = generate(&options).unwrap();
+        assert_eq!(level.size_tiles(), (8, 10));
+        assert!(Solver::new(&level).solve().is_some());
+        // the same seed gives the same level
+        let again = generate(&options).unwrap();
+        assert_eq!(serde_json::to_string(&level).unwrap(), serde_json::to_string(&again).unwrap());
+    }
+}
+
+#[test]
+fn harder_targets_make_harder_levels(){
+    let rate = |difficulty| {
+        let level = generate(&GeneratorOptions { difficulty, seed: 3, ..Default::default() }).unwrap();
+        Difficulty::of(&level).score.unwrap()
+    };
+    assert!(rate(2.0) < rate(8.0));
+}
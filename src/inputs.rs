@@ -1,23 +1,184 @@
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
 use raylib;
+use raylib::consts::KeyboardKey;
+use serde::{Serialize, Deserialize};
+use crate::rhythm::Sec;
 
 pub type Location = (i32,i32);
 
+/// Something the player did, and when they did it in seconds on the game clock
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "InputRepr", into = "InputRepr")]
 pub enum Input{
-    Key(raylib::consts::KeyboardKey),
-    Selection(Location)
+    Key(KeyboardKey, Sec),
+    Selection(Location, Sec)
 }
 
+impl Input {
+    pub fn time(&self) -> Sec {
+        match self {
+            Input::Key(_, time) | Input::Selection(_, time) => *time
+        }
+    }
+}
+
+/// A way the player can move on the grid
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    Up,
+    Down,
+    Left,
+    Right,
+}
+
+impl Direction {
+    pub const ALL: [Direction; 4] = [Direction::Up, Direction::Down, Direction::Left, Direction::Right];
+
+    /// The direction a key moves the player in, if any
+    pub fn from_key(key: KeyboardKey) -> Option<Direction> {
+        match key {
+            KeyboardKey::KEY_W | KeyboardKey::KEY_UP => Some(Direction::Up),
+            KeyboardKey::KEY_A | KeyboardKey::KEY_LEFT => Some(Direction::Left),
+            KeyboardKey::KEY_S | KeyboardKey::KEY_DOWN => Some(Direction::Down),
+            KeyboardKey::KEY_D | KeyboardKey::KEY_RIGHT => Some(Direction::Right),
+            _ => None
+        }
+    }
+
+    /// The key to press to move this way
+    pub fn key(&self) -> KeyboardKey {
+        match self {
+            Direction::Up => KeyboardKey::KEY_W,
+            Direction::Left => KeyboardKey::KEY_A,
+            Direction::Down => KeyboardKey::KEY_S,
+            Direction::Right => KeyboardKey::KEY_D,
+        }
+    }
+
+    /// How far a move goes along each axis of the player's position
+    pub fn offset(&self) -> (i32, i32) {
+        match self {
+            Direction::Up => (0, -1),
+            Direction::Left => (-1, 0),
+            Direction::Down => (0, 1),
+            Direction::Right => (1, 0),
+        }
+    }
+}
+
+/// How inputs are written to replays. Keys are stored as raylib's key codes,
+/// since raylib's own types don't serialize
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum InputRepr {
+    Key { key: u32, time: Sec },
+    Selection { selection: Location, time: Sec },
+}
+
+impl TryFrom<InputRepr> for Input {
+    type Error = String;
+
+    fn try_from(value: InputRepr) -> Result<Self, Self::Error> {
+        match value {
+            InputRepr::Key { key, time } => raylib::core::input::key_from_i32(key as i32)
+                .map(|key| Input::Key(key, time))
+                .ok_or_else(|| format!("unknown key code {}", key)),
+            InputRepr::Selection { selection, time } => Ok(Input::Selection(selection, time))
+        }
+    }
+}
 
-pub fn get_inputs(rh: &mut raylib::RaylibHandle) -> Vec<Input> {
+impl From<Input> for InputRepr {
+    fn from(value: Input) -> Self {
+        match value {
+            Input::Key(key, time) => InputRepr::Key { key: key as u32, time },
+            Input::Selection(selection, time) => InputRepr::Selection { selection, time }
+        }
+    }
+}
+
+/// Everything pressed since the last call, all stamped `now`
+pub fn get_inputs(rh: &mut raylib::RaylibHandle, now: Sec) -> Vec<Input> {
     let mut inputs = vec!();
     while let Some(key) = rh.get_key_pressed(){
-        inputs.push(Input::Key(key));
+        inputs.push(Input::Key(key, now));
     }
     if rh.is_mouse_button_pressed(raylib::consts::MouseButton::MOUSE_LEFT_BUTTON){
-        inputs.push(Input::Selection((rh.get_mouse_x(),rh.get_mouse_y())));
+        inputs.push(Input::Selection((rh.get_mouse_x(),rh.get_mouse_y()), now));
     }
 
     inputs
 }
 
+/// Records key presses on mki's input thread as they happen, so they're timed
+/// to when they were pressed rather than to the frame that picked them up
+pub struct InputCollector {
+    presses: Arc<Mutex<Vec<(KeyboardKey, Instant)>>>,
+    /// Whether the hook has reported anything yet. It needs read access to the
+    /// input devices, so until it does, key presses come from raylib instead
+    hooked: bool,
+}
 
+impl InputCollector {
+    pub fn new() -> Self {
+        let presses = Arc::new(Mutex::new(vec![]));
+        let sink = presses.clone();
+        mki::bind_any_key(mki::Action::callback_kb(move |key| {
+            if let Some(key) = to_raylib(key) {
+                sink.lock().unwrap().push((key, Instant::now()));
+            }
+        }));
+        Self { presses, hooked: false }
+    }
+
+    /// Everything pressed since the last call, given the game clock reads `now` at this instant
+    pub fn collect(&mut self, rh: &mut raylib::RaylibHandle, now: Sec) -> Vec<Input> {
+        let polled = get_inputs(rh, now);
+        let presses: Vec<_> = self.presses.lock().unwrap().drain(..).collect();
+        self.hooked |= !presses.is_empty();
+        if !self.hooked {
+            return polled;
+        }
+        let instant = Instant::now();
+        // the hook hears every key on the system, not just ones meant for us
+        let focused = rh.is_window_focused();
+        let mut inputs: Vec<Input> = presses.into_iter()
+            .filter(|_| focused)
+            .map(|(key, at)| Input::Key(key, now - instant.duration_since(at).as_secs_f64()))
+            .collect();
+        inputs.extend(polled.into_iter().filter(|i| matches!(i, Input::Selection(..))));
+        inputs
+    }
+}
+
+impl Default for InputCollector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Drop for InputCollector {
+    fn drop(&mut self) {
+        mki::remove_any_key_bind();
+    }
+}
+
+/// The raylib key for an mki one, for the keys the game cares about
+fn to_raylib(key: mki::Keyboard) -> Option<KeyboardKey> {
+    use mki::Keyboard as M;
+    use KeyboardKey as K;
+    Some(match key {
+        M::A => K::KEY_A, M::B => K::KEY_B, M::C => K::KEY_C, M::D => K::KEY_D,
+        M::E => K::KEY_E, M::F => K::KEY_F, M::G => K::KEY_G, M::H => K::KEY_H,
+        M::I => K::KEY_I, M::J => K::KEY_J, M::K => K::KEY_K, M::L => K::KEY_L,
+        M::M => K::KEY_M, M::N => K::KEY_N, M::O => K::KEY_O, M::P => K::KEY_P,
+        M::Q => K::KEY_Q, M::R => K::KEY_R, M::S => K::KEY_S, M::T => K::KEY_T,
+        M::U => K::KEY_U, M::V => K::KEY_V, M::W => K::KEY_W, M::X => K::KEY_X,
+        M::Y => K::KEY_Y, M::Z => K::KEY_Z,
+        M::Up => K::KEY_UP, M::Down => K::KEY_DOWN, M::Left => K::KEY_LEFT, M::Right => K::KEY_RIGHT,
+        M::Space => K::KEY_SPACE, M::Enter => K::KEY_ENTER, M::Escape => K::KEY_ESCAPE,
+        _ => return None
+    })
+}
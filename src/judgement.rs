@@ -0,0 +1,147 @@
+use serde::{Serialize, Deserialize};
+use crate::rhythm::Sec;
+
+/// How well a move landed on the beat
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Judgement {
+    Perfect,
+    Great,
+    Good,
+    /// Too far from the beat to count; the move doesn't happen
+    Miss,
+}
+
+impl Judgement {
+    const ALL: [Judgement; 4] = [Judgement::Perfect, Judgement::Great, Judgement::Good, Judgement::Miss];
+
+    fn points(&self) -> u64 {
+        match self {
+            Judgement::Perfect => 300,
+            Judgement::Great => 200,
+            Judgement::Good => 100,
+            Judgement::Miss => 0,
+        }
+    }
+
+    /// How much the judgement counts towards accuracy
+    fn weight(&self) -> f64 {
+        match self {
+            Judgement::Perfect => 1.0,
+            Judgement::Great => 0.7,
+            Judgement::Good => 0.4,
+            Judgement::Miss => 0.0,
+        }
+    }
+}
+
+/// How far from the beat a move can land, in seconds, and still get each judgement.
+/// Anything past `good` is a miss
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct JudgementWindows {
+    pub perfect: Sec,
+    pub great: Sec,
+    pub good: Sec,
+}
+
+impl Default for JudgementWindows {
+    fn default() -> Self {
+        JudgementWindows { perfect: 0.04, great: 0.08, good: 0.15 }
+    }
+}
+
+impl JudgementWindows {
+    /// Judge a move `offset` seconds from the beat, early or late
+    pub fn judge(&self, offset: Sec) -> Judgement {
+        let offset = offset.abs();
+        if offset <= self.perfect {
+            Judgement::Perfect
+        } else if offset <= self.great {
+            Judgement::Great
+        } else if offset <= self.good {
+            Judgement::Good
+        } else {
+            Judgement::Miss
+        }
+    }
+}
+
+/// Running totals of how well the player has been moving
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Score {
+    pub points: u64,
+    /// Moves in a row without a miss
+    pub combo: u32,
+    pub max_combo: u32,
+    /// how many of each judgement, in the order of `Judgement::ALL`
+    counts: [u32; 4],
+}
+
+impl Score {
+    pub fn record(&mut self, judgement: Judgement) {
+        self.counts[Judgement::ALL.iter().position(|j| *j == judgement).unwrap()] += 1;
+        if judgement == Judgement::Miss {
+            self.combo = 0;
+        } else {
+            self.combo += 1;
+            self.max_combo = self.max_combo.max(self.combo);
+            // a little bonus for every ten in a row
+            self.points += judgement.points() * (10 + (self.combo / 10) as u64) / 10;
+        }
+    }
+
+    pub fn count(&self, judgement: Judgement) -> u32 {
+        Judgement::ALL.iter().zip(self.counts)
+            .find(|(j, _)| **j == judgement)
+            .map(|(_, n)| n)
+            .unwrap_or(0)
+    }
+
+    /// Fraction of a perfect run, from 0 to 1. A run with no moves is perfect
+    pub fn accuracy(&self) -> f64 {
+        let total: u32 = self.counts.iter().sum();
+        if total == 0 {
+            return 1.0;
+        }
+        let weighted: f64 = Judgement::ALL.iter().zip(self.counts)
+            .map(|(j, n)| j.weight() * n as f64)
+            .sum();
+        weighted / total as f64
+    }
+
+    /// Letter grade for the run
+    pub fn grade(&self) -> &'static str {
+        match self.accuracy() {
+            a if a >= 0.95 => "S",
+            a if a >= 0.85 => "A",
+            a if a >= 0.7 => "B",
+            a if a >= 0.5 => "C",
+            _ => "D",
+        }
+    }
+}
+
+#[test]
+fn judging(){
+    let windows = JudgementWindows::default();
+    assert_eq!(windows.judge(0.0), Judgement::Perfect);
+    assert_eq!(windows.judge(-0.05), Judgement::Great);
+    assert_eq!(windows.judge(0.12), Judgement::Good);
+    assert_eq!(windows.judge(-0.2), Judgement::Miss);
+
+    let tight = JudgementWindows { perfect: 0.01, great: 0.02, good: 0.03 };
+    assert_eq!(tight.judge(0.025), Judgement::Good);
+}
+
+#[test]
+fn scoring(){
+    let mut score = Score::default();
+    for j in [Judgement::Perfect, Judgement::Perfect, Judgement::Great, Judgement::Miss, Judgement::Good] {
+        score.record(j);
+    }
+    assert_eq!(score.points, 900);
+    assert_eq!(score.combo, 1);
+    assert_eq!(score.max_combo, 3);
+    assert_eq!(score.count(Judgement::Perfect), 2);
+    assert!((score.accuracy() - 3.1 / 5.0).abs() < 1e-9);
+    assert_eq!(score.grade(), "C");
+}
@@ -1,16 +1,39 @@
 use std::{borrow::BorrowMut, io::BufReader};
 use std::io;
-use inputs::Input;
+use inputs::{Direction, Input};
+use audio::{AudioBackend, LevelMusic, NullAudio};
+use calibration::Settings;
+use judgement::{Judgement, JudgementWindows, Score};
+use timestep::FixedStep;
+use replay::Replay;
+use validation::Problem;
+use state::LevelState;
 use raylib::prelude::*;
 use serde::*;
-use tiles::{Tile, TileMap};
+use tiles::{Tile, TileKind, TileMap};
 use array2d::Array2D;
 
 
 pub mod tiles;
 pub mod inputs;
 pub mod rhythm;
+pub mod tempo;
+pub mod audio;
+pub mod synth;
+pub mod calibration;
+pub mod judgement;
+pub mod timestep;
+pub mod replay;
+pub mod view;
+pub mod script;
+pub mod solver;
+pub mod difficulty;
+pub mod generator;
+pub mod validation;
+pub mod format;
+pub mod state;
 use rhythm::*;
+use tempo::TempoMap;
 
 
 macro_rules! vec2 {
@@ -25,8 +48,8 @@ macro_rules! vec2 {
 }
 
 /// What states the player can be in
-#[derive(Debug, Default,Clone, Copy)]
-enum PlayerState {
+#[derive(Debug, Default,Clone, Copy, PartialEq, Eq)]
+pub enum PlayerState {
     #[default]
     Playing,
     Cleared,
@@ -43,90 +66,121 @@ pub struct Player{
     size: f32,
     /// the rhythm the player pulses in
     rhythm: Rhythm,
-    /// how large the map is 
-    /// TODO: get this out of here
-    map_size: (usize,usize),
-    last_moved: Option<f64>,
-    state: PlayerState
+    /// the hit of `rhythm` the player last moved on, so each beat only gets one move
+    last_moved: Option<i64>,
+    /// which way the player last went, for ice to keep them going
+    heading: Option<(i32, i32)>,
+    state: PlayerState,
+    /// how close to the beat moves have to be
+    windows: JudgementWindows,
+    score: Score,
 }
 
 impl Player {
-    /// How far around the beat you can move
-    const MOVEMENT_WINDOW: Sec = 0.15;
-
-    pub fn new(position: (usize, usize), tempo: BPM, map_size: (usize,usize)) -> Self{
+    pub fn new(position: (usize, usize), windows: JudgementWindows) -> Self{
         Self {position,size: 1.0,
-            rhythm: Rhythm::new(1,tempo,[0]),
-            map_size,
+            rhythm: Rhythm::new(1,[0]),
             last_moved: None,
+            windows,
             ..Default::default()
             }
     }
 
-    pub fn update(&mut self, delta: Sec, inputs: &[Input]){
-        self.rhythm.update(delta);
+    pub fn score(&self) -> &Score {
+        &self.score
+    }
+
+    /// Where on the grid the player is, as (row, column)
+    pub fn position(&self) -> (usize, usize) {
+        self.position
+    }
+
+    pub fn state(&self) -> PlayerState {
+        self.state
+    }
+
+    pub fn update(&mut self, clock: &Conductor, level: &mut Level, inputs: &[Input]){
         for inpt in inputs.iter() {
-            if let Input::Key(k) = inpt{
-                let direction = match k {
-                    KeyboardKey::KEY_W | KeyboardKey::KEY_UP => (0, -1),
-                    KeyboardKey::KEY_A | KeyboardKey::KEY_LEFT => (-1, 0),
-                    KeyboardKey::KEY_S | KeyboardKey::KEY_DOWN => (0, 1),
-                    KeyboardKey::KEY_D | KeyboardKey::KEY_RIGHT => (1,0),
-                    _ => (0,0)
-                };
-                self.move_(vec2!(direction))
+            if let Input::Key(k, time) = inpt{
+                if let Some(direction) = Direction::from_key(*k) {
+                    self.move_(clock, level, vec2!(direction.offset()), *time);
+                }
             };
         }
-        if let Some(pos) = self.last_moved {
-            if (self.rhythm.beat() != pos.trunc()) || (self.rhythm.position() < pos) {
-                self.last_moved = None;
-            }
-        }
     }
 
-    /// How large the 
-    pub fn size(&self) -> f32 {
-        let t = self.rhythm.position().fract();
+    /// How large the player is drawn, pulsing with the beat
+    pub fn size(&self, clock: &Conductor) -> f32 {
+        let t = self.rhythm.measure_position(clock.visual_beat()).fract();
         let tween = 0.25 * (-1.0 * (8.0 * t).log2().powi(2)).exp() + 1.0;
         (tween * self.size as f64) as f32
     }
 
-    /// Movement
-    pub fn move_(&mut self, direction: Vector2){
-        let new_position = vec2!(self.position) + direction;
-        if self.rhythm.in_window(Player::MOVEMENT_WINDOW) && (self.last_moved.is_none()) {
-            if (new_position.x >= 0.0) && (new_position.x < self.map_size.0 as f32){
-                self.position.0  = new_position.x as usize
-            }
-            if (new_position.y >= 0.0) && (new_position.y < self.map_size.1 as f32){
-                self.position.1 = new_position.y as usize
+    /// Movement around `level`, pressed at `time` on the clock. The move is judged on how close it
+    /// lands to the nearest beat; a miss, or a second move on the same beat, goes nowhere.
+    /// So does a well-timed move into a wall
+    pub fn move_(&mut self, clock: &Conductor, level: &mut Level, direction: Vector2, time: Sec) -> Judgement {
+        let judgement = match self.rhythm.nearest_hit(clock.input_beat_at(time)) {
+            Some((hit, offset)) if self.last_moved != Some(hit) => {
+                let judgement = self.windows.judge(clock.to_seconds(offset));
+                if judgement != Judgement::Miss {
+                    self.last_moved = Some(hit);
+                }
+                judgement
+            },
+            _ => Judgement::Miss
+        };
+        if judgement != Judgement::Miss {
+            let offset = (direction.x as i32, direction.y as i32);
+            let to = step_on(&level.tiles, &level.state, self.position, offset);
+            if to != self.position {
+                self.heading = Some(offset);
+                self.position = level.state.land(&level.tiles, to, offset, clock.input_beat_at(time).round());
             }
-            self.last_moved = Some(self.rhythm.position());
         }
+        self.score.record(judgement);
+        judgement
     }
 
 }
 
 
-#[derive(Debug,Serialize,Deserialize)]
-pub struct TileDimensions {
-    pub tile_width: i32,
-    pub tile_height: i32,
-    pub row_gap: i32,
-    pub column_gap: i32,
+/// Where moving `offset` from `position` lands, staying put along any axis
+/// that would leave a map of `map_size`
+pub fn step_within(position: (usize, usize), offset: (i32, i32), map_size: (usize, usize)) -> (usize, usize) {
+    let along = |at: usize, by: i32, size: usize| {
+        let to = at as i64 + by as i64;
+        if to >= 0 && to < size as i64 { to as usize } else { at }
+    };
+    (along(position.0, offset.0, map_size.0), along(position.1, offset.1, map_size.1))
 }
 
-impl TileDimensions {
-    pub fn top_left(&self, x: i32, y: i32) -> (i32,i32){
-        (x * (self.tile_width + self.row_gap),
-         y * (self.tile_height + self.column_gap)
-    )
+/// Where the tiles carry a player standing on `position` as `beat` starts, having been going
+/// `heading`, and which way they're going after. Portals along the way send them on, and a
+/// push onto another tile that acts on the same beat carries on from there, until the player
+/// comes to rest or would come back round to a cell they've already passed through
+pub fn forced_move(tiles: &TileMap, state: &mut LevelState, position: (usize, usize), heading: Option<(i32, i32)>, beat: Beat)
+    -> ((usize, usize), Option<(i32, i32)>) {
+    let (mut at, mut heading) = (position, heading);
+    let mut passed = vec![position];
+    while let Some(push) = state.get(tiles, at.0, at.1).and_then(|t| t.push(beat, heading)) {
+        let to = step_on(tiles, state, at, push);
+        if to == at || passed.contains(&to) {
+            break;
+        }
+        passed.push(to);
+        at = state.land(tiles, to, push, beat);
+        passed.push(at);
+        heading = Some((push.0.signum(), push.1.signum()));
     }
+    (at, heading)
+}
 
-    pub fn center(&self, x: i32, y: i32) -> (i32, i32) {
-        let (xtl, ytl) = self.top_left(x, y);
-        (xtl + (self.tile_width - self.row_gap) / 2, ytl + (self.tile_height - self.column_gap) / 2)
-    }
+/// Where moving `offset` from `position` on `tiles` lands: as `step_within`, except that
+/// walls, shut doors and one-way tiles pointing the other way keep the player where they are
+pub fn step_on(tiles: &TileMap, state: &LevelState, position: (usize, usize), offset: (i32, i32)) -> (usize, usize) {
+    let to = step_within(position, offset, (tiles.num_rows(), tiles.num_columns()));
+    if state.enterable(tiles, to, offset) { to } else { position }
 }
 
 
@@ -134,8 +188,19 @@ impl TileDimensions {
 pub struct Game{
     level: Option<Level>,
     player: Option<Player>,
-    camera: Camera2D,
-    dimensions: TileDimensions
+    /// The one clock every rhythm in the level is read against
+    clock: Conductor,
+    /// Where the level's music plays
+    audio: Box<dyn AudioBackend>,
+    settings: Settings,
+    /// Splits frames into equal steps for the simulation
+    stepper: FixedStep,
+    /// inputs stamped later than the steps simulated so far
+    queued: Vec<Input>,
+    /// Where the current level was loaded from
+    level_path: Option<String>,
+    /// The current attempt, if it's being recorded
+    recording: Option<Replay>,
 }
 
 
@@ -143,7 +208,32 @@ pub struct Game{
 pub enum RCError{
     Array2D(array2d::Error),
     IO(std::io::Error),
-    Json(serde_json::Error)
+    Json(serde_json::Error),
+    /// A rhythm pattern string that doesn't parse
+    Pattern(PatternError),
+    /// Music that couldn't be loaded or played
+    Audio(String),
+    /// A level that parsed but can't be played, with everything wrong with it
+    Validation(Vec<Problem>),
+}
+
+impl std::fmt::Display for RCError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RCError::Array2D(e) => write!(f, "{}", e),
+            RCError::IO(e) => write!(f, "{}", e),
+            RCError::Json(e) => write!(f, "{}", e),
+            RCError::Pattern(e) => write!(f, "{}", e),
+            RCError::Audio(e) => write!(f, "{}", e),
+            RCError::Validation(problems) => {
+                write!(f, "{} problem{} with the level:", problems.len(), if problems.len() == 1 { "" } else { "s" })?;
+                for problem in problems {
+                    write!(f, "\n  {}", problem)?;
+                }
+                Ok(())
+            }
+        }
+    }
 }
 
 impl  From<array2d::Error> for RCError {
@@ -164,96 +254,151 @@ impl From<serde_json::Error> for RCError {
     }
 }
 
+impl From<PatternError> for RCError {
+    fn from(value: PatternError) -> Self {
+        Self::Pattern(value)
+    }
+}
+
+impl Default for Game {
+    fn default() -> Self {
+        Game::new()
+    }
+}
+
 impl Game {
 
-    pub fn new(camera: Camera2D, dimensions: TileDimensions) -> Self {
+    pub fn new() -> Self {
         Self {
             level: None, 
-            camera,
             player: None,
-            dimensions
+            clock: Conductor::default(),
+            audio: Box::new(NullAudio::new()),
+            settings: Settings::default(),
+            stepper: FixedStep::default(),
+            queued: vec![],
+            level_path: None,
+            recording: None,
         }
     }
 
+    /// Use the latency offsets in `settings` from now on
+    pub fn apply_settings(&mut self, settings: Settings) {
+        self.settings = settings;
+        self.clock.set_offsets(settings.input_offset, settings.visual_offset);
+    }
+
+    /// Play level music through `audio` instead of silently keeping time
+    pub fn set_audio(&mut self, audio: Box<dyn AudioBackend>) {
+        self.audio = audio;
+    }
+
     pub fn load_level(&mut self, level_path: &str)->Result<(),RCError>{
-        let mut level: Level = serde_json::from_reader(BufReader::new(
-            std::fs::File::open(level_path)?))?;
-        level.set_tempo();
-        self.player = Some(Player::new(level.starting_location, level.tempo, level.size_tiles()));
+        let level = Level::load(level_path)?;
+        self.play_level(level)?;
+        self.level_path = Some(level_path.to_string());
+        if let Some(recording) = &mut self.recording {
+            recording.level = level_path.to_string();
+        }
+        Ok(())
+    }
+
+    /// Start playing `level` from the top
+    pub fn play_level(&mut self, level: Level)->Result<(),RCError>{
+        self.clock = Conductor::new(level.tempo.clone());
+        self.clock.set_offsets(self.settings.input_offset, self.settings.visual_offset);
+        self.player = Some(Player::new(level.starting_location, level.judgement));
+        self.stepper.reset();
+        self.queued.clear();
+        audio::start_soundtrack(self.audio.as_mut(), &level)?;
         self.level = Some(level);
+        self.level_path = None;
+        if let Some(recording) = &self.recording {
+            self.record(recording.seed);
+        }
         Ok(())
     }
 
-    pub fn draw(&self, handle: &mut RaylibDrawHandle){
-        if let (Some(level), Some(player)) = (& self.level, &self.player) {
-            {
-            let mut mode2d = handle.begin_mode2D(self.camera);
-                for ((row,col), tile) in level.tiles.enumerate_column_major() {
-                    let (x_tl,y_tl) = self.dimensions.top_left(row as i32, col as i32);
-                    mode2d.draw_rectangle(x_tl,y_tl,
-                        self.dimensions.tile_width,
-                        self.dimensions.tile_height,
-                        tile.get_color()
-                    );
-                }
-                let (player_x,player_y)  = self.dimensions.center(
-                    player.position.0 as i32, player.position.1 as i32);
-                let player_radius = self.dimensions.tile_height as f32 * player.size() / 3.0;
-                mode2d.draw_circle(player_x, player_y, 
-                    player_radius, Color::YELLOW);
-            }
-        
-            let (rows, columns) = level.size_tiles();
-            let height = (rows as i32) * self.dimensions.tile_height;
-            let width = (columns as i32) * self.dimensions.tile_width;
-            let (height, width) = (height as f64, width as f64);
-            let mut draw_msg_box = || {
-                handle.draw_rectangle(
-                    (0.2 * width) as i32, 
-                    (0.2 * height) as i32, 
-                    (0.4 * width) as i32, 
-                    (0.2 * height) as i32, Color::GRAY);
-            };
-            
-            match player.state {
-                PlayerState::Cleared => {
-                    draw_msg_box();
-                    handle.draw_text("Level cleared!", 3 * width as i32/ 10, 
-                    3 * height as i32 * 10, 18, Color::BLACK)
-        
-                },
-                PlayerState::Died => {
-                    draw_msg_box();
-                    handle.draw_text("You died", 3 * width as i32/ 10, 3 * height as i32 / 10, 18, Color::BLACK)
-                }, // need to implement this
-                PlayerState::Playing => {}
+    /// Record inputs into a replay from here on. Loading a level or restarting
+    /// after dying starts the recording over, so it always covers the current attempt
+    pub fn record(&mut self, seed: u64) {
+        let level = self.level_path.as_deref().unwrap_or_default();
+        self.recording = Some(Replay::new(level, seed, self.settings));
+    }
+
+    /// The replay recorded so far, if recording
+    pub fn recording(&self) -> Option<&Replay> {
+        self.recording.as_ref()
+    }
+
+    /// Simulate as many fixed steps as `delta` covers. Each input is handled in the
+    /// step its timestamp falls in, so the outcome doesn't depend on the frame rate
+    pub fn update(&mut self, delta:f64, inputs:&[Input]){
+        self.queued.extend_from_slice(inputs);
+        for _ in 0..self.stepper.advance(delta) {
+            let end = self.clock.time() + self.stepper.step();
+            let (now, later): (Vec<Input>, _) = self.queued.drain(..).partition(|i| i.time() < end);
+            self.queued = later;
+            if let Some(recording) = &mut self.recording {
+                recording.inputs.extend_from_slice(&now);
+                recording.duration = end;
             }
+            self.step(&now);
         }
     }
 
-    pub fn update(&mut self, delta:f64, inputs:&[Input]){
+    fn step(&mut self, inputs:&[Input]){
+        let delta = self.stepper.step();
         if let (Some(level), Some(player)) = (&mut self.level, &mut self.player) {
-            level.update(delta, inputs);
+            let before = self.clock.time();
+            self.audio.update(delta);
+            audio::follow_music(&mut self.clock, self.audio.as_ref(), level.music.as_ref(), delta);
+            level.update(&self.clock, inputs);
+            for event in level.drain_events() {
+                if let Some(cue) = level.tiles.get(event.position.0, event.position.1).and_then(|t| t.tile.sound.as_ref()) {
+                    if cue.plays_on(event.on) {
+                        self.audio.play_cue(cue);
+                    }
+                }
+            }
             match player.state{
             PlayerState::Playing => {
-                player.update(delta, inputs);
+                player.update(&self.clock, level, inputs);
+                // tiles act on the player for a beat once the last late press on it could have
+                // landed, unless they moved on that beat themselves; being carried uses up the
+                // beat's move. Crumbling tiles only wear under a player who stayed put
+                let late = player.windows.good;
+                let settled = |time: Sec| self.clock.input_beat_at(time - late);
+                let hit = settled(before).ceil();
+                if hit < settled(self.clock.time()) {
+                    let stayed = player.last_moved != Some(hit as i64);
+                    level.state.wear(&level.tiles, stayed.then_some(player.position));
+                    if stayed {
+                        let (to, heading) = forced_move(&level.tiles, &mut level.state, player.position, player.heading, hit);
+                        if to != player.position {
+                            player.position = to;
+                            player.heading = heading;
+                            player.last_moved = Some(hit as i64);
+                        }
+                    }
+                }
                 let (row, col) = player.position;
-                match level.tiles.get(row,col){
+                // standing is timed like the presses that got the player there, so
+                // late-landing input doesn't leave them a step behind the tiles
+                match level.cell(row,col){
                     None => {player.state = PlayerState::Died}
                     Some(tile) => {
                         if tile.goal {
                             player.state = PlayerState::Cleared;
-                        } else if tile.rhythm.as_ref().is_some() {
-                            if !tile.on(Level::WINDOW + 0.1).unwrap(){
-                                player.state = PlayerState::Died;
-                            }
-                        } 
+                        } else if tile.deadly(self.clock.input_beat(), self.clock.to_beats(Level::GRACE)) {
+                            player.state = PlayerState::Died;
+                        }
                     }
                 }
             },
             PlayerState::Died=> {
                 if inputs.iter().any(|i|{
-                    matches!(i,Input::Key(KeyboardKey::KEY_R))
+                    matches!(i,Input::Key(KeyboardKey::KEY_R, _))
                 }){
                     self.reset()
                 }
@@ -263,68 +408,406 @@ impl Game {
     }
     }
 
+    /// Step through to `until` seconds on the game clock, a fixed step at a time,
+    /// handing each of `inputs` to the game as the clock reaches it
+    pub fn run(&mut self, until: Sec, inputs: &[Input]) {
+        let step = self.stepper.step();
+        while self.time() < until {
+            let now = self.time();
+            let due: Vec<Input> = inputs.iter()
+                .filter(|i| i.time() >= now && i.time() < now + step)
+                .copied().collect();
+            self.update(step, &due);
+        }
+    }
+
+    pub fn level(&self) -> Option<&Level> {
+        self.level.as_ref()
+    }
+
+    pub fn player(&self) -> Option<&Player> {
+        self.player.as_ref()
+    }
+
+    pub fn clock(&self) -> &Conductor {
+        &self.clock
+    }
+
+    /// Seconds into the level on the game clock, counting time handed to
+    /// `update` that hasn't been simulated yet
+    pub fn time(&self) -> Sec {
+        self.clock.time() + self.stepper.pending()
+    }
+
+    pub fn player_state(&self) -> Option<PlayerState> {
+        self.player.as_ref().map(|p| p.state)
+    }
+
+    /// How the player has been doing so far
+    pub fn score(&self) -> Option<&Score> {
+        self.player.as_ref().map(|p| p.score())
+    }
+
     fn reset(&mut self) {
         if let (Some(level), Some(player)) = (&mut self.level, &mut self.player) {
             player.position = level.starting_location;
             player.state = PlayerState::Playing;
-            player.rhythm.reset();
-            for t in level.tiles.iter_mut(){
-                if let Some(r) = t.rhythm.as_mut(){
-                    r.reset();
-                }
+            player.last_moved = None;
+            player.heading = None;
+            player.score = Score::default();
+            self.clock.reset();
+            self.stepper.reset();
+            self.queued.clear();
+            if let Some(recording) = &mut self.recording {
+                recording.inputs.clear();
+                recording.duration = 0.0;
             }
-    
+            level.reset();
+            // best effort: if the music went missing mid-level, carry on without it
+            let _ = audio::start_soundtrack(self.audio.as_mut(), level);
         }
     }
 }
 
 
 
+/// A rhythmic tile turning on or off
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TileEvent {
+    pub position: (usize, usize),
+    /// whether the tile appeared, rather than vanished
+    pub on: bool,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Level {
+    /// Which version of the format the level is in; `Level::load` upgrades older ones
+    #[serde(default = "current_format")]
+    pub format_version: u32,
     #[serde(flatten)]
     pub tiles: tiles::TileMap,
     pub starting_location: (usize, usize),
-    pub tempo: BPM,
+    /// Either a single BPM or a map of tempo and meter changes; 120 BPM if left out
+    #[serde(default)]
+    pub tempo: TempoMap,
+    /// Song to play along to; the beat follows it when there is one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub music: Option<LevelMusic>,
+    /// How close to the beat moves have to land
+    #[serde(default)]
+    pub judgement: JudgementWindows,
+    /// Which cells were on at the last update, to spot them flipping
+    #[serde(skip)]
+    lit: Option<Array2D<bool>>,
+    /// Flips seen since the events were last drained
+    #[serde(skip)]
+    events: Vec<TileEvent>,
+    /// Switches, keys, doors and crumbling tiles as they stand
+    #[serde(skip)]
+    state: LevelState,
+}
+
+fn current_format() -> u32 {
+    format::FORMAT_VERSION
 }
 
 impl Level {
     const WINDOW: f64 = 0.05;
-    pub fn new(mut tiles: Array2D<Tile>, starting_location: (usize, usize), tempo: BPM) -> Self{
-        for i in 0..tiles.num_rows() {
-            for j in 0..tiles.num_columns(){
-                if let Some(tile) = tiles.get_mut(i, j){
-                    if let Some(rhythm) = &mut tile.rhythm{
-                        rhythm.set_tempo(tempo);
-                    }
-                }
-            }
-        }
-
+    /// How long either side of its ticks a rhythmic tile can still be stood on, in seconds
+    pub const GRACE: Sec = Level::WINDOW + 0.1;
+    pub fn new<T: Into<TempoMap>>(tiles: Array2D<Tile>, starting_location: (usize, usize), tempo: T) -> Self{
         Level {
+            format_version: format::FORMAT_VERSION,
             tiles: <&Array2D<Tile> as Into<TileMap>>::into(&tiles),
             starting_location,
-            tempo: tempo
+            tempo: tempo.into(),
+            music: None,
+            judgement: JudgementWindows::default(),
+            lit: None,
+            events: vec![],
+            state: LevelState::default(),
         }
     }
 
-    pub fn set_tempo(&mut self){
-        for tile in self.tiles.iter_mut() {
-            if let Some(r) = &mut tile.rhythm {
-                r.duration = beat_length(self.tempo);
-            }
+    /// Read a level file, upgrading it from older formats, and check it can be played
+    pub fn load(path: &str) -> Result<Level, RCError> {
+        let mut json: serde_json::Value = serde_json::from_reader(BufReader::new(std::fs::File::open(path)?))?;
+        format::migrate(&mut json)?;
+        let level: Level = serde_json::from_value(json)?;
+        level.validate()?;
+        Ok(level)
+    }
+
+    /// Check everything the game relies on about a level, listing all that's wrong at once
+    pub fn validate(&self) -> Result<(), RCError> {
+        let mut problems = self.tiles.problems();
+        // only worth looking at what's in the cells once every cell points at a real tile
+        let cells_ok = problems.is_empty();
+        if cells_ok && !self.tiles.enumerate_column_major().any(|(_, t)| t.goal) {
+            problems.push(Problem::new("$.tiles", "no tile on the map is a goal"));
+        }
+        let (rows, columns) = self.size_tiles();
+        if self.starting_location.0 >= rows || self.starting_location.1 >= columns {
+            problems.push(Problem::new("$.starting_location", format!(
+                "{:?} is outside the {} by {} grid", self.starting_location, rows, columns)));
+        } else if let Some(kind @ (TileKind::Wall | TileKind::Pit)) = cells_ok
+            .then(|| self.tiles.get(self.starting_location.0, self.starting_location.1)).flatten().map(|t| t.kind) {
+            problems.push(Problem::new("$.starting_location", format!("the player can't start on a {:?}", kind).to_lowercase()));
         }
+        problems.extend(self.tempo.problems());
+        let j = &self.judgement;
+        if !(0.0 < j.perfect && j.perfect <= j.great && j.great <= j.good) {
+            problems.push(Problem::new("$.judgement", "windows have to be above 0 and get wider from perfect to good"));
+        }
+        if problems.is_empty() { Ok(()) } else { Err(RCError::Validation(problems)) }
     }
 
     pub fn size_tiles(&self) -> (usize, usize) {
         (self.tiles.num_rows(), self.tiles.num_columns())
     }
 
-    pub fn update(&mut self, delta: Sec, _inputs: &[Input]){
-        for tile in self.tiles.iter_mut(){
-            tile.update(delta)
+    /// Look for rhythmic tiles that turned on or off since the last update.
+    /// Each flip is reported once, however many frames it spans
+    pub fn update(&mut self, clock: &Conductor, _inputs: &[Input]){
+        let beat = clock.beat();
+        let (rows, columns) = self.size_tiles();
+        let now = Array2D::from_iter_column_major(
+            self.cells().map(|(_, tile)| tile.on(beat, 0.0).unwrap_or(false)),
+            rows, columns).unwrap();
+        if let Some(lit) = &self.lit {
+            for ((r, c), on) in now.enumerate_column_major() {
+                if lit.get(r, c) != Some(on) {
+                    self.events.push(TileEvent { position: (r, c), on: *on });
+                }
+            }
         }
+        self.lit = Some(now);
+    }
+
+    /// Take the tile flips seen since the last call
+    pub fn drain_events(&mut self) -> Vec<TileEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    /// Forget everything that happened while playing, ready to start over
+    pub fn reset(&mut self){
+        self.lit = None;
+        self.events.clear();
+        self.state = LevelState::default();
+    }
+
+    /// The tile at a cell, with switches, keys, doors and crumbling tiles as they stand
+    pub fn cell(&self, r: usize, c: usize) -> Option<tiles::PlacedTile<'_>> {
+        self.state.get(&self.tiles, r, c)
+    }
+
+    /// Every cell as it stands, going down each column in turn
+    pub fn cells(&self) -> impl Iterator<Item = ((usize, usize), tiles::PlacedTile<'_>)> {
+        self.state.cells(&self.tiles)
+    }
+
+    /// Switches, keys, doors and crumbling tiles as they stand, to save
+    pub fn state(&self) -> &LevelState {
+        &self.state
+    }
+
+    /// Put switches, keys, doors and crumbling tiles back as they were saved
+    pub fn restore(&mut self, state: LevelState) {
+        self.state = state;
     }
     
 }
 
+#[test]
+fn tile_flips_are_reported_once(){
+    let blink = Tile::from(&Color::RED, Some(Rhythm::new(2, [0])));
+    let mut level = Level::new(Array2D::filled_with(blink, 1, 2), (0, 0), 120.0);
+    level.tiles.set_phase(0, 1, 1.0);
+    let mut clock = Conductor::new(120.0);
+    let mut events = vec![];
+    // two beats at 60fps
+    for _ in 0..60 {
+        level.update(&clock, &[]);
+        events.extend(level.drain_events());
+        clock.advance(1.0 / 60.0);
+    }
+    assert_eq!(events, vec![
+        TileEvent { position: (0, 0), on: false },
+        TileEvent { position: (0, 1), on: true },
+    ]);
+}
+
+#[test]
+fn moves_are_judged(){
+    let mut level = Level::new(Array2D::filled_with(Tile::default(), 3, 3), (1, 1), 120.0);
+    let mut player = Player::new((1, 1), JudgementWindows::default());
+    let mut clock = Conductor::new(120.0);
+    clock.advance(0.01);
+    assert_eq!(player.move_(&clock, &mut level, vec2!(1, 0), clock.time()), Judgement::Perfect);
+    assert_eq!(player.position, (2, 1));
+    // one move per beat
+    assert_eq!(player.move_(&clock, &mut level, vec2!(0, 1), clock.time()), Judgement::Miss);
+    // half a beat off is too far
+    clock.advance(0.24);
+    assert_eq!(player.move_(&clock, &mut level, vec2!(0, 1), clock.time()), Judgement::Miss);
+    // early for the next beat still counts
+    clock.advance(0.2);
+    assert_eq!(player.move_(&clock, &mut level, vec2!(0, 1), clock.time()), Judgement::Great);
+    assert_eq!(player.position, (2, 2));
+    assert_eq!(player.score().combo, 1);
+    assert_eq!(player.score().count(Judgement::Miss), 2);
+}
+
+#[test]
+fn moves_are_judged_at_press_time(){
+    let mut level = Level::new(Array2D::filled_with(Tile::default(), 3, 3), (1, 1), 120.0);
+    let mut player = Player::new((1, 1), JudgementWindows::default());
+    let mut clock = Conductor::new(120.0);
+    // the frame lands 90ms after the beat, but the key went down right on it
+    clock.advance(1.09);
+    player.update(&clock, &mut level, &[Input::Key(KeyboardKey::KEY_D, 1.0)]);
+    assert_eq!(player.score().count(Judgement::Perfect), 1);
+    assert_eq!(player.position, (2, 1));
+}
+
+#[test]
+fn tile_kinds(){
+    use script::Script;
+    use tiles::{column, floor, goal, kind};
+    // walk right from a floor tile, over `middle`, to the goal
+    let play = |middle: Tile, script: Script, until: Beat| {
+        let level = column([floor(), middle, goal()]);
+        let mut game = Game::new();
+        game.play_level(level).unwrap();
+        script.run(&mut game, until);
+        (game.player().unwrap().position(), game.player_state().unwrap())
+    };
+    let right = || Script::new().press(1.0, KeyboardKey::KEY_D);
+
+    assert_eq!(play(kind(TileKind::Wall), right(), 3.0), ((0, 0), PlayerState::Playing));
+    assert_eq!(play(kind(TileKind::OneWay(Direction::Left)), right(), 3.0), ((0, 0), PlayerState::Playing));
+    let through = right().press(2.0, KeyboardKey::KEY_D);
+    assert_eq!(play(kind(TileKind::OneWay(Direction::Right)), through, 3.0), ((2, 0), PlayerState::Cleared));
+    assert_eq!(play(kind(TileKind::Pit), right(), 1.5), ((1, 0), PlayerState::Died));
+    // spikes that come up every other beat are safe to cross in between
+    let spikes = Tile { rhythm: Some(Rhythm::new(2, [0])), ..kind(TileKind::Spikes) };
+    assert_eq!(play(spikes.clone(), right(), 1.9), ((1, 0), PlayerState::Playing));
+    assert_eq!(play(spikes, right(), 2.1), ((1, 0), PlayerState::Died));
+
+    // maps from before kinds are all floor
+    let tile: Tile = serde_json::from_str(r#"{"color": {"r": 0, "g": 0, "b": 0, "a": 255}, "rhythm": null}"#).unwrap();
+    assert_eq!(tile.kind, TileKind::Floor);
+    let tile: Tile = serde_json::from_str(r#"{"color": {"r": 0, "g": 0, "b": 0, "a": 255}, "rhythm": null, "kind": {"one_way": "up"}}"#).unwrap();
+    assert_eq!(tile.kind, TileKind::OneWay(Direction::Up));
+}
+
+#[test]
+fn forced_moves(){
+    use tiles::{column, floor, goal, kind};
+    let row = |tiles: Vec<Tile>| column(tiles).tiles;
+    let right = Direction::Right;
+
+    // conveyors chain into each other on the same beat
+    let belt = row(vec![kind(TileKind::Conveyor(right)), kind(TileKind::Conveyor(right)), floor()]);
+    assert_eq!(forced_move(&belt, &mut LevelState::default(), (0, 0), None, 0.0), ((2, 0), Some((1, 0))));
+    // but stop short of going round in circles
+    let circle = row(vec![kind(TileKind::Conveyor(right)), kind(TileKind::Conveyor(Direction::Left))]);
+    assert_eq!(forced_move(&circle, &mut LevelState::default(), (0, 0), None, 0.0), ((1, 0), Some((1, 0))));
+    // springs jump two cells, unless that's off the map
+    let spring = row(vec![kind(TileKind::Spring(right)), kind(TileKind::Wall), floor()]);
+    assert_eq!(forced_move(&spring, &mut LevelState::default(), (0, 0), None, 0.0).0, (2, 0));
+    assert_eq!(forced_move(&row(vec![kind(TileKind::Spring(right)), floor()]), &mut LevelState::default(), (0, 0), None, 0.0).0, (0, 0));
+    // ice keeps the player going the way they were
+    let rink = row(vec![floor(), kind(TileKind::Ice), kind(TileKind::Ice), floor()]);
+    assert_eq!(forced_move(&rink, &mut LevelState::default(), (1, 0), Some((1, 0)), 0.0).0, (3, 0));
+    assert_eq!(forced_move(&rink, &mut LevelState::default(), (1, 0), None, 0.0).0, (1, 0));
+    // conveyors with a rhythm only push on its beats
+    let slow = Tile { rhythm: Some(Rhythm::new(2, [0])), ..kind(TileKind::Conveyor(right)) };
+    let slow = row(vec![slow, floor()]);
+    assert_eq!(forced_move(&slow, &mut LevelState::default(), (0, 0), None, 1.0).0, (0, 0));
+    assert_eq!(forced_move(&slow, &mut LevelState::default(), (0, 0), None, 2.0).0, (1, 0));
+
+    // in a game, stepping onto a conveyor carries the player on at the next beat,
+    // and the solver finds the same way through
+    let level = column([floor(), kind(TileKind::Conveyor(right)), floor(), goal()]);
+    let solution = solver::Solver::new(&level).solve().unwrap();
+    assert_eq!(solution.moves, vec![(0, right), (2, right)]);
+    let mut game = Game::new();
+    game.play_level(level).unwrap();
+    let inputs = [Input::Key(KeyboardKey::KEY_D, 0.5), Input::Key(KeyboardKey::KEY_D, 1.5)];
+    game.run(0.75, &inputs);
+    assert_eq!(game.player().unwrap().position(), (1, 0));
+    game.run(1.25, &inputs);
+    assert_eq!(game.player().unwrap().position(), (2, 0));
+    game.run(2.0, &inputs);
+    assert_eq!(game.player_state(), Some(PlayerState::Cleared));
+
+    // a press that's late but still in the window steps off before the conveyor pushes,
+    // as the solver expects
+    let level = Level { starting_location: (1, 0), ..column([goal(), kind(TileKind::Conveyor(right)), kind(TileKind::Pit)]) };
+    assert_eq!(solver::Solver::new(&level).solve().unwrap().moves, vec![(0, Direction::Left)]);
+    let mut game = Game::new();
+    game.play_level(level).unwrap();
+    game.run(0.5, &[Input::Key(KeyboardKey::KEY_A, 0.05)]);
+    assert_eq!(game.player_state(), Some(PlayerState::Cleared));
+}
+
+#[test]
+fn crumbling_tiles_break_underfoot(){
+    use tiles::{column, floor, goal, kind};
+    let level = column([floor(), kind(TileKind::Crumbling { beats: 1, respawn: None }), goal()]);
+    assert_eq!(solver::Solver::new(&level).solve().unwrap().moves.len(), 2);
+    let mut game = Game::new();
+    game.play_level(level).unwrap();
+
+    // standing on it through a whole beat breaks it, once the chance to move on the next has passed
+    game.run(1.25, &[Input::Key(KeyboardKey::KEY_D, 0.5)]);
+    assert_eq!(game.player_state(), Some(PlayerState::Died));
+    assert!(game.level().unwrap().cell(1, 0).unwrap().broken());
+    // starting over puts it back, and moving straight on gets across
+    game.update(0.05, &[Input::Key(KeyboardKey::KEY_R, game.time())]);
+    assert_eq!(game.level().unwrap().cell(1, 0).unwrap().worn, 0);
+    // even pressing late, as long as it's in the window
+    game.run(1.3, &[Input::Key(KeyboardKey::KEY_D, 0.5), Input::Key(KeyboardKey::KEY_D, 1.05)]);
+    assert_eq!(game.player_state(), Some(PlayerState::Cleared));
+}
+
+#[test]
+fn input_offset_moves_deaths_too(){
+    // a tile that's gone from beat 1 to 3, under the player from the start
+    let blink = Tile::from(&Color::RED, Some(Rhythm::new(4, [0, 3])));
+    let play = |input_offset: Sec| {
+        let mut game = Game::new();
+        game.apply_settings(Settings { input_offset, ..Default::default() });
+        game.play_level(Level::new(Array2D::filled_with(blink.clone(), 1, 1), (0, 0), 120.0)).unwrap();
+        game.run(0.75, &[]);
+        game.player_state().unwrap()
+    };
+    assert_eq!(play(0.0), PlayerState::Died);
+    assert_eq!(play(0.2), PlayerState::Playing);
+}
+
+#[test]
+fn frame_rate_doesnt_change_the_game(){
+    // step right onto each beat, all the way to the goal
+    let presses: Vec<Input> = (1..=6).map(|b| Input::Key(KeyboardKey::KEY_D, 0.5 * b as f64 + 0.01)).collect();
+    let play = |frames: &[Sec]| {
+        let mut game = Game::new();
+        game.load_level("maps/begin.json").unwrap();
+        for delta in frames.iter().cycle() {
+            if game.time() > 4.0 {
+                break;
+            }
+            let inputs: Vec<Input> = presses.iter()
+                .filter(|i| i.time() >= game.time() && i.time() < game.time() + delta)
+                .copied().collect();
+            game.update(*delta, &inputs);
+        }
+        let player = game.player.unwrap();
+        (player.position, player.state, player.score)
+    };
+    let smooth = play(&[1.0 / 60.0]);
+    assert_eq!(smooth.0, (6, 0));
+    assert_eq!(smooth.1, PlayerState::Cleared);
+    assert_eq!(smooth, play(&[1.0 / 24.0, 1.0 / 144.0, 0.1]));
+}
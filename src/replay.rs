@@ -0,0 +1,84 @@
+use std::io::BufReader;
+use serde::{Serialize, Deserialize};
+use crate::calibration::Settings;
+use crate::inputs::Input;
+use crate::rhythm::Sec;
+use crate::{Game, RCError};
+
+/// Everything needed to play an attempt at a level back exactly as it happened
+#[derive(Debug, Default, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Replay {
+    /// Level file, relative to where the game is run from
+    pub level: String,
+    /// Seed the level was generated from, for levels that were
+    #[serde(default)]
+    pub seed: u64,
+    /// Latency offsets in use while recording, since they change how presses are judged
+    #[serde(default)]
+    pub settings: Settings,
+    /// Seconds on the game clock the recording ran for
+    pub duration: Sec,
+    /// Every input the game handled, oldest first
+    pub inputs: Vec<Input>,
+}
+
+impl Replay {
+    pub fn new(level: &str, seed: u64, settings: Settings) -> Self {
+        Replay { level: level.to_string(), seed, settings, duration: 0.0, inputs: vec![] }
+    }
+
+    pub fn load(path: &str) -> Result<Replay, RCError> {
+        Ok(serde_json::from_reader(BufReader::new(std::fs::File::open(path)?))?)
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), RCError> {
+        serde_json::to_writer(std::fs::File::create(path)?, self)?;
+        Ok(())
+    }
+
+    /// Inputs made from `from` up to but not including `to` seconds
+    pub fn inputs_between(&self, from: Sec, to: Sec) -> Vec<Input> {
+        self.inputs.iter().filter(|i| i.time() >= from && i.time() < to).copied().collect()
+    }
+
+    /// Load the level into `game` and play the whole replay through it, with no window
+    pub fn play(&self, game: &mut Game) -> Result<(), RCError> {
+        game.apply_settings(self.settings);
+        game.load_level(&self.level)?;
+        game.run(self.duration, &self.inputs);
+        Ok(())
+    }
+}
+
+#[test]
+fn replays_play_back_the_same(){
+    use raylib::consts::KeyboardKey;
+    use crate::PlayerState;
+    let mut game = Game::new();
+    game.load_level("maps/begin.json").unwrap();
+    game.record(7);
+    // two steps right on the beat, then one between beats that goes nowhere
+    let presses = [(1.0, KeyboardKey::KEY_D), (1.5, KeyboardKey::KEY_D), (1.75, KeyboardKey::KEY_S)];
+    while game.time() < 3.0 {
+        let end = game.time() + 1.0 / 60.0;
+        let inputs: Vec<Input> = presses.iter()
+            .filter(|(t, _)| *t >= game.time() && *t < end)
+            .map(|(t, k)| Input::Key(*k, *t))
+            .collect();
+        game.update(1.0 / 60.0, &inputs);
+    }
+    let recording = game.recording().unwrap().clone();
+    assert_eq!(recording.level, "maps/begin.json");
+    assert_eq!(recording.seed, 7);
+    assert_eq!(recording.inputs.len(), 3);
+
+    let json = serde_json::to_string(&recording).unwrap();
+    let loaded: Replay = serde_json::from_str(&json).unwrap();
+    assert_eq!(loaded, recording);
+
+    let mut replayed = Game::new();
+    loaded.play(&mut replayed).unwrap();
+    assert_eq!(replayed.player_state(), Some(PlayerState::Playing));
+    assert_eq!(replayed.player_state(), game.player_state());
+    assert_eq!(replayed.score(), game.score());
+}
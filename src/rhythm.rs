@@ -1,27 +1,196 @@
 use serde::{Serialize,Deserialize};
-use std::{hash::Hash, collections::HashSet};
+use std::{hash::Hash, collections::HashSet, fmt, str::FromStr};
+use crate::tempo::TempoMap;
+use crate::RCError;
+use crate::validation::Problem;
 
 pub type Sec = f64;
 pub type BPM = f64;
+pub type Beat = f64;
 
-pub fn beat_length(tempo: BPM) -> Sec {
-    60_f64 / tempo as Sec
+/// The global song position. Every rhythm in a level is read against
+/// one of these, so all pulses on screen line up no matter how long
+/// the level has been running.
+#[derive(Debug, Default, Clone)]
+pub struct Conductor {
+    /// Tempo and time signature of the level
+    tempo: TempoMap,
+    /// Seconds since the start of the level
+    time: Sec,
+    /// How late the player's presses land, in seconds
+    input_offset: Sec,
+    /// How late the screen shows things, in seconds
+    visual_offset: Sec,
 }
 
+impl Conductor {
+    pub fn new<T: Into<TempoMap>>(tempo: T) -> Self {
+        Conductor {
+            tempo: tempo.into(),
+            ..Default::default()
+        }
+    }
+
+    /// Latency to allow for when judging presses and drawing, as measured by calibration
+    pub fn set_offsets(&mut self, input_offset: Sec, visual_offset: Sec){
+        self.input_offset = input_offset;
+        self.visual_offset = visual_offset;
+    }
+
+    pub fn tempo(&self) -> &TempoMap {
+        &self.tempo
+    }
 
+    pub fn advance(&mut self, delta: Sec){
+        self.time += delta;
+    }
+
+    pub fn reset(&mut self){
+        self.time = 0.0;
+    }
+
+    /// How far the clock can drift from the music before it jumps back in line
+    const SYNC_TOLERANCE: Sec = 0.05;
+
+    /// Pull the clock towards `time` as reported by the music stream. Streams only
+    /// report in buffer-sized steps, so small gaps are eased out instead of jumped
+    pub fn sync(&mut self, time: Sec){
+        let drift = time - self.time;
+        if drift.abs() > Conductor::SYNC_TOLERANCE {
+            self.time = time;
+        } else {
+            self.time += drift * 0.1;
+        }
+    }
+
+    /// Song position in seconds
+    pub fn time(&self) -> Sec {
+        self.time
+    }
+
+    /// Song position in beats
+    pub fn beat(&self) -> Beat {
+        self.tempo.beat_at(self.time)
+    }
+
+    /// Song position to judge presses against, since they arrive late
+    pub fn input_beat(&self) -> Beat {
+        self.input_beat_at(self.time)
+    }
+
+    /// `input_beat` for a press made at `time` rather than now
+    pub fn input_beat_at(&self, time: Sec) -> Beat {
+        self.tempo.beat_at(time - self.input_offset)
+    }
 
+    /// Song position to draw, running ahead so it shows up on time
+    pub fn visual_beat(&self) -> Beat {
+        self.tempo.beat_at(self.time + self.visual_offset)
+    }
+
+    /// Convert a span of seconds into beats at the current tempo
+    pub fn to_beats(&self, seconds: Sec) -> Beat {
+        seconds / self.tempo.beat_length_at(self.beat())
+    }
+
+    /// Convert a span of beats into seconds at the current tempo
+    pub fn to_seconds(&self, beats: Beat) -> Sec {
+        beats * self.tempo.beat_length_at(self.beat())
+    }
+}
+
+
+/// A repeating pattern of beats. Rhythms don't keep time themselves;
+/// they are queried with a song position from a [`Conductor`].
+///
+/// Each beat is split into `subdivision` ticks, and `beats` lists the
+/// ticks that play. With the default subdivision of 1 a tick is just a beat.
+///
+/// In level JSON a rhythm is either written out field by field, as a
+/// pattern string like `"x..x..x."` (see [`Rhythm::from_str`]), or built
+/// from other rhythms, e.g. `{"euclid": [3, 8, 2]}` or `{"union": ["x..", "x."]}`.
 #[derive(Debug,Default,Clone,PartialEq, Serialize,Deserialize)]
+#[serde(try_from = "RhythmRepr", into = "RhythmRepr")]
 pub struct Rhythm {
     /// Number of beats in a measure
     pub length: usize,
-    /// which beats to play; zero-indexed
+    /// which ticks to play; zero-indexed
     pub beats: HashSet<usize>,
-    /// Length of a beat, in seconds
-    #[serde(skip)]
-    pub duration: Sec,
-    /// the current time within the measure
-    #[serde(skip)]
-    time: Sec,
+    /// Ticks per beat: 2 for eighth notes, 3 for triplets, and so on
+    pub subdivision: usize,
+    /// How far into each pair of ticks the second one lands.
+    /// 0.5 is straight, 2/3 is a triplet swing
+    pub swing: Option<f64>,
+}
+
+/// The ways a rhythm can be written in level JSON
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum RhythmRepr {
+    Pattern(String),
+    Euclid { euclid: EuclidArgs },
+    Union { union: (Rhythm, Rhythm) },
+    Intersection { intersection: (Rhythm, Rhythm) },
+    Xor { xor: (Rhythm, Rhythm) },
+    Invert { invert: Box<Rhythm> },
+    Rotate { rotate: (Box<Rhythm>, isize) },
+    Fields(RhythmFields),
+}
+
+/// `[hits, steps]` or `[hits, steps, rotation]`
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum EuclidArgs {
+    Plain(usize, usize),
+    Rotated(usize, usize, isize),
+}
+
+#[derive(Serialize, Deserialize)]
+struct RhythmFields {
+    length: usize,
+    beats: HashSet<usize>,
+    #[serde(default = "default_subdivision")]
+    subdivision: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    swing: Option<f64>,
+}
+
+fn default_subdivision() -> usize {
+    1
+}
+
+impl TryFrom<RhythmRepr> for Rhythm {
+    type Error = RCError;
+
+    fn try_from(repr: RhythmRepr) -> Result<Self, Self::Error> {
+        match repr {
+            RhythmRepr::Pattern(pattern) => pattern.parse(),
+            RhythmRepr::Euclid { euclid: EuclidArgs::Plain(hits, steps) } => Rhythm::euclid(hits, steps, 0),
+            RhythmRepr::Euclid { euclid: EuclidArgs::Rotated(hits, steps, rotation) } => Rhythm::euclid(hits, steps, rotation),
+            RhythmRepr::Union { union: (a, b) } => a.union(&b),
+            RhythmRepr::Intersection { intersection: (a, b) } => a.intersection(&b),
+            RhythmRepr::Xor { xor: (a, b) } => a.xor(&b),
+            RhythmRepr::Invert { invert } => invert.invert(),
+            RhythmRepr::Rotate { rotate: (r, ticks) } => Ok(r.rotate(ticks)),
+            RhythmRepr::Fields(RhythmFields { length, beats, subdivision, swing }) => {
+                Ok(Rhythm { length, beats, subdivision, swing })
+            }
+        }
+    }
+}
+
+impl From<Rhythm> for RhythmRepr {
+    fn from(rhythm: Rhythm) -> Self {
+        match rhythm.to_pattern() {
+            Some(pattern) => RhythmRepr::Pattern(pattern),
+            None => RhythmRepr::Fields(RhythmFields {
+                length: rhythm.length,
+                beats: rhythm.beats,
+                subdivision: rhythm.subdivision,
+                swing: rhythm.swing
+            })
+        }
+    }
 }
 
 fn canonical(v: f64) -> i64{
@@ -32,110 +201,659 @@ impl Hash for Rhythm{
 
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
         self.length.hash(state);
-        canonical(self.duration).hash(state);
-        for b in &self.beats{
-            b.hash(state);
-        }
-        canonical(self.time).hash(state);
+        let mut beats: Vec<_> = self.beats.iter().collect();
+        beats.sort();
+        beats.hash(state);
+        self.subdivision.hash(state);
+        self.swing.map(canonical).hash(state);
     }
 }
 
 impl Rhythm{
 
-    pub fn set_tempo(&mut self, tempo: BPM){
-        self.duration = beat_length(tempo)
+    /// Ticks per beat, treating an unset subdivision as whole beats
+    pub fn ticks_per_beat(&self) -> usize {
+        self.subdivision.max(1)
     }
 
-    pub fn update(&mut self, delta: Sec){
-        self.time += delta;
-        self.time %= self.duration * self.length as Sec;
+    /// Number of ticks in a measure
+    pub fn ticks(&self) -> usize {
+        self.length * self.ticks_per_beat()
     }
-    pub fn reset(&mut self){
-        self.time = 0.0;
+
+    /// Where `position` falls within a measure of this rhythm
+    pub fn measure_position(&self, position: Beat) -> Beat {
+        position.rem_euclid(self.length as Beat)
+    }
+
+    /// Where `tick` starts within the measure, in beats, after swing
+    pub fn tick_start(&self, tick: usize) -> Beat {
+        let sub = self.ticks_per_beat();
+        let (beat, within) = (tick / sub, tick % sub);
+        let straight = within as Beat / sub as Beat;
+        let offset = match self.swing {
+            // the second tick of each pair is pushed late
+            Some(swing) if within % 2 == 1 => {
+                (within - 1) as Beat / sub as Beat + 2.0 * swing / sub as Beat
+            },
+            _ => straight
+        };
+        beat as Beat + offset
+    }
+
+    /// How long `tick` lasts, in beats
+    pub fn tick_length(&self, tick: usize) -> Beat {
+        self.tick_start(tick + 1) - self.tick_start(tick)
     }
 
-    pub fn on(&self) -> bool {
-        self.beats.contains(& (self.position().trunc() as usize))
+    /// Which tick of the measure `position` falls in
+    pub fn tick_at(&self, position: Beat) -> usize {
+        let sub = self.ticks_per_beat();
+        let position = self.measure_position(position);
+        let beat = position.trunc();
+        let scaled = (position - beat) * sub as Beat;
+        let within = match self.swing {
+            Some(swing) => {
+                let pair = (scaled / 2.0).trunc();
+                let first = 2 * pair as usize;
+                let into_pair = scaled / 2.0 - pair;
+                if first + 1 < sub && into_pair >= swing { first + 1 } else { first }
+            },
+            None => scaled.trunc() as usize
+        };
+        beat as usize * sub + within.min(sub - 1)
     }
 
-    pub fn position(&self) -> f64 {
-        self.time / self.duration
+    /// Everything wrong with the rhythm, with `path` leading to it in a level's JSON
+    pub fn problems(&self, path: &str) -> Vec<Problem> {
+        let mut problems = vec![];
+        if self.length == 0 {
+            problems.push(Problem::new(format!("{}.length", path), "a rhythm has to last at least one beat"));
+        }
+        if self.subdivision == 0 {
+            problems.push(Problem::new(format!("{}.subdivision", path), "beats have to be split into at least one tick"));
+        } else if let Some(tick) = self.beats.iter().filter(|t| **t >= self.ticks()).min() {
+            problems.push(Problem::new(format!("{}.beats", path),
+                format!("tick {} is past the end of the rhythm's {} ticks", tick, self.ticks())));
+        }
+        if self.swing.is_some_and(|s| !(0.0..=1.0).contains(&s)) {
+            problems.push(Problem::new(format!("{}.swing", path), "swing has to be between 0 and 1"));
+        }
+        problems
     }
 
-    pub fn beat(&self) -> f64 {
-        self.position().trunc()
+    /// The hit closest to `position`, as a count of ticks since beat 0,
+    /// and how many beats after it `position` is (negative if before)
+    pub fn nearest_hit(&self, position: Beat) -> Option<(i64, Beat)> {
+        let measure = (position / self.length as Beat).floor() as i64;
+        (measure - 1..=measure + 1).flat_map(|m| {
+            self.beats.iter().map(move |tick| {
+                let at = m as Beat * self.length as Beat + self.tick_start(*tick);
+                (m * self.ticks() as i64 + *tick as i64, position - at)
+            })
+        }).min_by(|a, b| a.1.abs().total_cmp(&b.1.abs()))
     }
 
-    pub fn in_window(&self, window_size: Sec)-> bool {
+    pub fn on_at(&self, position: Beat) -> bool {
+        self.beats.contains(&self.tick_at(position))
+    }
+
+    /// Whether `position` is within `window` beats of one of our ticks
+    pub fn in_window_at(&self, position: Beat, window: Beat)-> bool {
+        let position = self.measure_position(position);
         self.beats.iter().any(
-            |beat| {
-                let distance = (*beat as f64 - self.position()).abs();
-                f64::min(distance, self.length as f64 - distance) < window_size / self.duration
+            |tick| {
+                let distance = (self.tick_start(*tick) - position).abs();
+                f64::min(distance, self.length as f64 - distance) < window
             }
         )
     }
 
-    pub fn on_beat(&self, window_size: Sec) -> bool {
+    /// Whether `position` is inside one of our ticks, widened by `window` beats on either side
+    pub fn on_beat_at(&self, position: Beat, window: Beat) -> bool {
+        let position = self.measure_position(position);
         self.beats.iter().any(
-            |beat| {
-                let beat = *beat as f64;
-                let window_rel = window_size / self.duration;
-                let start = (beat - window_rel) % self.length as f64;
-                let end = (beat + 1.0 + window_rel) % self.length as f64;
-                
-                (start <= self.position() && self.position() < end) || 
-                    (start > end && (start <= self.position() || self.position() < end))
+            |tick| {
+                let start = (self.tick_start(*tick) - window).rem_euclid(self.length as f64);
+                let end = (self.tick_start(*tick + 1) + window).rem_euclid(self.length as f64);
+
+                (start <= position && position < end) ||
+                    (start > end && (start <= position || position < end))
             }
         )
     }
 
-    pub fn new<T>(length: usize, tempo: BPM, beats: T) -> Self 
+    pub fn new<T>(length: usize, beats: T) -> Self
+    where T: IntoIterator<Item=usize> {
+        Rhythm::subdivided(length, 1, beats)
+    }
+
+    /// A rhythm with `subdivision` ticks per beat, playing on `ticks`
+    pub fn subdivided<T>(length: usize, subdivision: usize, ticks: T) -> Self
     where T: IntoIterator<Item=usize> {
         Rhythm{
-            length, 
-            duration: beat_length(tempo),
-            beats: beats.into_iter().collect(),
-            time: 0.0
+            length,
+            beats: ticks.into_iter().collect(),
+            subdivision,
+            swing: None,
+        }
+    }
+
+    pub fn with_swing(mut self, swing: f64) -> Self {
+        self.swing = Some(swing);
+        self
+    }
+
+    /// Rhythms built out of others are held to the same limits as patterns, so a
+    /// level can't ask for more ticks than there's memory for. Gives back the
+    /// length and subdivision, or an error at `path` if either is zero or overflowed
+    fn sized(path: &str, length: Option<usize>, subdivision: Option<usize>) -> Result<(usize, usize), RCError> {
+        let problem = |message: String| RCError::Validation(vec![Problem::new(path, message)]);
+        match (length, subdivision) {
+            (Some(0), _) => Err(problem("a rhythm has to last at least one beat".into())),
+            (Some(length), Some(subdivision)) if length <= PatternParser::MAX_STEPS
+                && length.checked_mul(subdivision).is_some_and(|ticks| ticks <= PatternParser::MAX_TICKS) => Ok((length, subdivision)),
+            _ => Err(problem(format!("rhythms can't be more than {} beats or {} ticks long",
+                PatternParser::MAX_STEPS, PatternParser::MAX_TICKS)))
+        }
+    }
+
+    /// `hits` spread as evenly as possible over `steps` beats, the Euclidean
+    /// rhythm E(hits, steps). `rotation` shifts every hit that many beats later.
+    pub fn euclid(hits: usize, steps: usize, rotation: isize) -> Result<Self, RCError> {
+        let (steps, _) = Rhythm::sized("$.euclid", Some(steps), Some(1))?;
+        let hits = hits.min(steps);
+        // Bjorklund's algorithm: keep pairing leftover rests onto the groups of hits
+        let mut groups = vec![vec![true]; hits];
+        let mut remainder = vec![vec![false]; steps - hits];
+        while !groups.is_empty() && remainder.len() > 1 {
+            let paired = groups.len().min(remainder.len());
+            let leftover = if groups.len() > paired {
+                groups.split_off(paired)
+            } else {
+                remainder.split_off(paired)
+            };
+            for (group, rest) in groups.iter_mut().zip(remainder) {
+                group.extend(rest);
+            }
+            remainder = leftover;
+        }
+        let steps_on = groups.into_iter().chain(remainder).flatten();
+        let pattern = Rhythm::new(steps, steps_on.enumerate().filter(|(_, on)| *on).map(|(i, _)| i));
+        Ok(pattern.rotate(rotation))
+    }
+
+    /// Every tick of this rhythm, repeated and rescaled onto a grid of
+    /// `length` beats with `subdivision` ticks each. Both have to be multiples of ours.
+    fn spread(&self, length: usize, subdivision: usize) -> HashSet<usize> {
+        if self.length == 0 {
+            return HashSet::new();
+        }
+        let scale = subdivision / self.ticks_per_beat();
+        let measure = self.length * subdivision;
+        (0..length / self.length).flat_map(
+            |m| self.beats.iter().map(move |t| m * measure + t * scale)
+        ).collect()
+    }
+
+    /// Lay two rhythms over each other on a common grid and combine their ticks with `op`.
+    /// The result lasts until both line up again; swing survives only if both agree on it
+    fn combine<F>(&self, other: &Rhythm, path: &str, op: F) -> Result<Rhythm, RCError>
+    where F: Fn(&HashSet<usize>, &HashSet<usize>) -> HashSet<usize> {
+        let (length, subdivision) = Rhythm::sized(path,
+            checked_lcm(self.length, other.length),
+            checked_lcm(self.ticks_per_beat(), other.ticks_per_beat()))?;
+        let beats = op(&self.spread(length, subdivision), &other.spread(length, subdivision));
+        Ok(Rhythm {
+            length,
+            beats,
+            subdivision,
+            swing: if self.swing == other.swing { self.swing } else { None }
+        })
+    }
+
+    /// Ticks where either rhythm plays
+    pub fn union(&self, other: &Rhythm) -> Result<Rhythm, RCError> {
+        self.combine(other, "$.union", |a, b| a | b)
+    }
+
+    /// Ticks where both rhythms play
+    pub fn intersection(&self, other: &Rhythm) -> Result<Rhythm, RCError> {
+        self.combine(other, "$.intersection", |a, b| a & b)
+    }
+
+    /// Ticks where exactly one of the rhythms plays
+    pub fn xor(&self, other: &Rhythm) -> Result<Rhythm, RCError> {
+        self.combine(other, "$.xor", |a, b| a ^ b)
+    }
+
+    /// Play on every tick this rhythm rests on, and rest on the rest
+    pub fn invert(&self) -> Result<Rhythm, RCError> {
+        let (length, subdivision) = Rhythm::sized("$.invert", Some(self.length), Some(self.ticks_per_beat()))?;
+        Ok(Rhythm {
+            beats: (0..length * subdivision).filter(|t| !self.beats.contains(t)).collect(),
+            ..self.clone()
+        })
+    }
+
+    /// Shift every tick `ticks` later, wrapping around the measure
+    pub fn rotate(&self, ticks: isize) -> Rhythm {
+        let n = self.ticks() as isize;
+        if n == 0 {
+            return self.clone();
+        }
+        Rhythm {
+            beats: self.beats.iter().map(|t| (*t as isize + ticks).rem_euclid(n) as usize).collect(),
+            ..self.clone()
         }
     }
 }
 
+/// Where a rhythm pattern went wrong, counted from 1
+#[derive(Debug, Clone, PartialEq)]
+pub struct PatternError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for PatternError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}, column {}: {}", self.line, self.column, self.message)
+    }
+}
+
+/// One step of a parsed pattern
+#[derive(Debug, Clone)]
+enum Step {
+    Hit,
+    Rest,
+    /// Steps squeezed evenly into the space of one
+    Tuplet(Vec<Step>),
+}
+
+impl Step {
+    /// How many ticks this step needs to place all of its hits, if that fits in a `usize`
+    fn resolution(&self) -> Option<usize> {
+        match self {
+            Step::Hit | Step::Rest => Some(1),
+            Step::Tuplet(steps) => steps.iter()
+                .try_fold(1, |a, step| checked_lcm(a, step.resolution()?))?
+                .checked_mul(steps.len())
+        }
+    }
+
+    fn place(&self, start: usize, span: usize, ticks: &mut HashSet<usize>) {
+        match self {
+            Step::Hit => { ticks.insert(start); },
+            Step::Rest => {},
+            Step::Tuplet(steps) => {
+                let span = span / steps.len();
+                for (i, step) in steps.iter().enumerate() {
+                    step.place(start + i * span, span, ticks);
+                }
+            }
+        }
+    }
+}
+
+struct PatternParser<'a> {
+    chars: std::iter::Peekable<std::str::Chars<'a>>,
+    line: usize,
+    column: usize,
+    /// how many brackets we're inside
+    depth: usize,
+}
+
+impl PatternParser<'_> {
+    /// Limits that keep a hostile pattern from eating all the memory or stack when a level loads
+    const MAX_REPEAT: usize = 1024;
+    const MAX_DEPTH: usize = 16;
+    const MAX_STEPS: usize = 4096;
+    const MAX_TICKS: usize = 1 << 16;
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next()?;
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+
+    fn error(&self, (line, column): (usize, usize), message: String) -> PatternError {
+        PatternError { line, column, message }
+    }
+
+    /// Parse steps until `close`, or the end of the pattern if there's nothing to close
+    fn sequence(&mut self, close: Option<(char, (usize, usize))>) -> Result<Vec<Step>, PatternError> {
+        let mut steps = vec![];
+        loop {
+            while self.chars.peek().is_some_and(|c| c.is_whitespace() || *c == '|') {
+                self.next();
+            }
+            let at = (self.line, self.column);
+            let step = match (self.next(), close) {
+                (None, None) => return Ok(steps),
+                (None, Some((c, opened))) => return Err(self.error(opened, format!("this bracket is never closed with '{}'", c))),
+                (Some(c), Some((close, _))) if c == close => return Ok(steps),
+                (Some('x' | 'X'), _) => vec![Step::Hit],
+                (Some('.' | '-'), _) => vec![Step::Rest],
+                (Some('['), _) => {
+                    let inner = self.nested(']', at)?;
+                    if inner.is_empty() {
+                        return Err(self.error(at, "empty tuplet".into()));
+                    }
+                    vec![Step::Tuplet(inner)]
+                },
+                (Some('('), _) => {
+                    let inner = self.nested(')', at)?;
+                    if inner.is_empty() {
+                        return Err(self.error(at, "empty group".into()));
+                    }
+                    inner
+                },
+                (Some(c), _) => return Err(self.error(at, format!("unexpected '{}'", c))),
+            };
+            let count = self.repeat()?;
+            if steps.len() + step.len() * count > Self::MAX_STEPS {
+                return Err(self.error(at, format!("patterns can't be more than {} steps long", Self::MAX_STEPS)));
+            }
+            for _ in 0..count {
+                steps.extend(step.iter().cloned());
+            }
+        }
+    }
+
+    /// Parse the steps inside a bracket opened at `at`, up to `close`
+    fn nested(&mut self, close: char, at: (usize, usize)) -> Result<Vec<Step>, PatternError> {
+        if self.depth == Self::MAX_DEPTH {
+            return Err(self.error(at, format!("brackets can't be nested more than {} deep", Self::MAX_DEPTH)));
+        }
+        self.depth += 1;
+        let inner = self.sequence(Some((close, at)));
+        self.depth -= 1;
+        inner
+    }
+
+    /// An optional `*n` after a step
+    fn repeat(&mut self) -> Result<usize, PatternError> {
+        if self.chars.peek() != Some(&'*') {
+            return Ok(1);
+        }
+        self.next();
+        let at = (self.line, self.column);
+        let mut digits = String::new();
+        while let Some(c) = self.chars.peek().filter(|c| c.is_ascii_digit()) {
+            digits.push(*c);
+            self.next();
+        }
+        if digits.is_empty() {
+            return Err(self.error(at, "expected a repeat count after '*'".into()));
+        }
+        match digits.parse() {
+            Ok(0) => Err(self.error(at, "can't repeat something zero times".into())),
+            Ok(count) if count <= Self::MAX_REPEAT => Ok(count),
+            _ => Err(self.error(at, format!("can't repeat something more than {} times", Self::MAX_REPEAT))),
+        }
+    }
+}
+
+impl FromStr for Rhythm {
+    type Err = RCError;
+
+    /// Parse a pattern like `"x..x..x."` or `"x-x- [xxx]"`.
+    ///
+    /// Each `x` is a hit lasting one beat, `.` or `-` is a rest. `[...]` squeezes
+    /// its contents into a single beat, so `[xxx]` is a triplet. `(...)` groups
+    /// steps so they can be repeated together with `*n`, as in `(x.)*4`.
+    /// Whitespace and `|` barlines are ignored. Repeats, nesting and how finely
+    /// tuplets divide the beat all have limits, so any pattern parses quickly.
+    fn from_str(pattern: &str) -> Result<Self, Self::Err> {
+        let mut parser = PatternParser { chars: pattern.chars().peekable(), line: 1, column: 1, depth: 0 };
+        let steps = parser.sequence(None)?;
+        if steps.is_empty() {
+            return Err(parser.error((1, 1), "empty pattern".into()).into());
+        }
+        let subdivision = steps.iter()
+            .try_fold(1, |a, step| checked_lcm(a, step.resolution()?))
+            .filter(|sub| sub.checked_mul(steps.len()).is_some_and(|ticks| ticks <= PatternParser::MAX_TICKS))
+            .ok_or_else(|| parser.error((1, 1), format!(
+                "tuplets can't divide the pattern into more than {} ticks", PatternParser::MAX_TICKS)))?;
+        let mut ticks = HashSet::new();
+        for (beat, step) in steps.iter().enumerate() {
+            step.place(beat * subdivision, subdivision, &mut ticks);
+        }
+        Ok(Rhythm::subdivided(steps.len(), subdivision, ticks))
+    }
+}
+
+impl Rhythm {
+    /// Write the rhythm in pattern notation, if it can be.
+    /// Swung rhythms and ones with ticks past the end of the measure can't.
+    pub fn to_pattern(&self) -> Option<String> {
+        if self.swing.is_some() || self.length == 0 || self.beats.iter().any(|t| *t >= self.ticks()) {
+            return None;
+        }
+        let sub = self.ticks_per_beat();
+        let step = |tick| if self.beats.contains(&tick) { 'x' } else { '.' };
+        let mut pattern = String::new();
+        for beat in 0..self.length {
+            if sub == 1 {
+                pattern.push(step(beat));
+            } else {
+                pattern.push('[');
+                pattern.extend((0..sub).map(|i| step(beat * sub + i)));
+                pattern.push(']');
+            }
+        }
+        Some(pattern)
+    }
+}
+
+pub fn gcd(a: usize, b: usize) -> usize {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+/// Least common multiple, taken as 0 if either is
+pub fn lcm(a: usize, b: usize) -> usize {
+    if a == 0 || b == 0 { 0 } else { a / gcd(a, b) * b }
+}
+
+fn checked_lcm(a: usize, b: usize) -> Option<usize> {
+    if a == 0 || b == 0 { Some(0) } else { (a / gcd(a, b)).checked_mul(b) }
+}
+
 #[test]
 fn test_rhythm(){
-    let mut r = Rhythm::new(2, 120.0, vec![1]);
-    assert_eq!(r.on(), false);
-    r.update(0.15);
-    assert_eq!(r.on(), false);
-    assert!((r.time - 0.15).abs() < 1e-6);
-    r.update(r.duration);
-    assert!(r.on());
-    assert!((1.15 - r.position()) < 1e-6);
-    r.update(r.duration);
-    assert_eq!(r.on(), false);
-    assert!((r.time - 0.15).abs() < 1e-6);
+    let r = Rhythm::new(2, vec![1]);
+    let mut clock = Conductor::new(120.0);
+    assert!(!r.on_at(clock.beat()));
+    clock.advance(0.15);
+    assert!(!r.on_at(clock.beat()));
+    assert!((clock.time() - 0.15).abs() < 1e-6);
+    clock.advance(0.5);
+    assert!(r.on_at(clock.beat()));
+    assert!((1.3 - clock.beat()).abs() < 1e-6);
+    clock.advance(0.5);
+    assert!(!r.on_at(clock.beat()));
+    assert!((r.measure_position(clock.beat()) - 0.3).abs() < 1e-6);
 }
 
 #[test]
 fn rhythm_window(){
-    let mut tr = Rhythm::new(2,120., vec![0]);
-    assert!((tr.duration-0.5) < 1e-6);
-    assert!(tr.in_window(1e-6));
-    tr.time = 0.04;
-    assert!(tr.in_window(0.05));
-    tr.time = 0.54;
-    assert!(!tr.in_window(0.05));
-    tr.time = 0.96;
-    assert!(tr.in_window(0.05));
-
-
-    let mut tr = Rhythm::new(4, 120., vec![0,2]);
-    tr.update(0.04);
-    assert!(tr.in_window(0.05));
-    tr.update(0.5 );
-    assert!(!tr.in_window(0.05));
-    tr.update(0.5 );
-    assert!(tr.in_window(0.05));
-    tr.update(0.5 );
-    assert!(!tr.in_window(0.05));
+    let tr = Rhythm::new(2, vec![0]);
+    let clock = Conductor::new(120.);
+    let window = clock.to_beats(0.05);
+    assert!(tr.in_window_at(0.0, clock.to_beats(1e-6)));
+    assert!(tr.in_window_at(0.08, window));
+    assert!(!tr.in_window_at(1.08, window));
+    assert!(tr.in_window_at(1.92, window));
+
+    let tr = Rhythm::new(4, vec![0,2]);
+    let mut clock = Conductor::new(120.);
+    clock.advance(0.04);
+    assert!(tr.in_window_at(clock.beat(), window));
+    clock.advance(0.5);
+    assert!(!tr.in_window_at(clock.beat(), window));
+    clock.advance(0.5);
+    assert!(tr.in_window_at(clock.beat(), window));
+    clock.advance(0.5);
+    assert!(!tr.in_window_at(clock.beat(), window));
+}
+
+#[test]
+fn subdivisions(){
+    // dotted eighths: hits on the first, fourth and seventh sixteenths of a beat pair
+    let r = Rhythm::subdivided(2, 4, [0, 3, 6]);
+    assert!(r.on_at(0.1));
+    assert!(!r.on_at(0.3));
+    assert!(r.on_at(0.8));
+    assert!(r.on_at(1.6));
+    assert!(!r.on_at(1.8));
+    assert!(r.in_window_at(0.74, 0.02));
+    assert!(!r.in_window_at(0.6, 0.02));
+
+    let triplets = Rhythm::subdivided(1, 3, [1]);
+    assert!(!triplets.on_at(0.3));
+    assert!(triplets.on_at(0.4));
+    assert!(!triplets.on_at(0.7));
+    assert!(triplets.on_beat_at(0.7, 0.05));
+}
+
+#[test]
+fn swing(){
+    let r = Rhythm::subdivided(1, 2, [1]).with_swing(2.0 / 3.0);
+    assert!((r.tick_start(1) - 2.0 / 3.0).abs() < 1e-9);
+    assert!((r.tick_length(0) - 2.0 / 3.0).abs() < 1e-9);
+    assert!(!r.on_at(0.6));
+    assert!(r.on_at(0.7));
+    assert!(r.in_window_at(0.65, 0.05));
+    assert!(!r.in_window_at(0.5, 0.05));
+}
+
+#[test]
+fn whole_beats_load_unchanged(){
+    let r: Rhythm = serde_json::from_str(r#"{"length": 2, "duration": 0.5, "beats": [1]}"#).unwrap();
+    assert_eq!(r, Rhythm::new(2, [1]));
+    assert!(r.on_at(1.5));
+    assert!(!r.on_at(0.5));
+}
+
+#[test]
+fn patterns(){
+    let r: Rhythm = "x..x..x.".parse().unwrap();
+    assert_eq!(r, Rhythm::new(8, [0, 3, 6]));
+
+    let r: Rhythm = "x-x- [xxx]".parse().unwrap();
+    assert_eq!(r, Rhythm::subdivided(5, 3, [0, 6, 12, 13, 14]));
+
+    let r: Rhythm = "(x.)*4".parse().unwrap();
+    assert_eq!(r, Rhythm::new(8, [0, 2, 4, 6]));
 
+    let r: Rhythm = "[x.x] [x[xx]]".parse().unwrap();
+    assert_eq!(r, Rhythm::subdivided(2, 12, [0, 8, 12, 18, 21]));
+}
+
+#[test]
+fn pattern_errors(){
+    let error = |pattern: &str| match pattern.parse::<Rhythm>() {
+        Err(RCError::Pattern(e)) => (e.line, e.column),
+        other => panic!("expected a pattern error, got {:?}", other)
+    };
+    assert_eq!(error("x..y"), (1, 4));
+    assert_eq!(error("x.\nx.]"), (2, 3));
+    assert_eq!(error("x [x."), (1, 3));
+    assert_eq!(error("x*"), (1, 3));
+    assert_eq!(error("  "), (1, 1));
+    // patterns that would take forever to expand stop at a limit
+    assert_eq!(error("x*99999999"), (1, 3));
+    assert_eq!(error("x*99999999999999999999999"), (1, 3));
+    assert_eq!(error("(((x*1000)*1000)*1000)"), (1, 3));
+    assert_eq!(error(&format!("{}x{}", "[".repeat(10000), "]".repeat(10000))), (1, 17));
+    assert_eq!(error(&format!("{}x{}", "[x.".repeat(12), "]".repeat(12))), (1, 1));
+}
+
+#[test]
+fn pattern_round_trip(){
+    let fields: Rhythm = serde_json::from_str(r#"{"length": 4, "beats": [1, 2], "subdivision": 2}"#).unwrap();
+    let json = serde_json::to_string(&fields).unwrap();
+    assert_eq!(json, r#""[.x][x.][..][..]""#);
+    let pattern: Rhythm = serde_json::from_str(&json).unwrap();
+    assert_eq!(fields, pattern);
+
+    let swung = Rhythm::subdivided(1, 2, [1]).with_swing(0.6);
+    let json = serde_json::to_string(&swung).unwrap();
+    assert_eq!(swung, serde_json::from_str(&json).unwrap());
+
+    let error = serde_json::from_str::<Rhythm>(r#""x.q""#).unwrap_err();
+    assert!(error.to_string().contains("line 1, column 3"));
+}
+
+#[test]
+fn euclidean(){
+    assert_eq!(Rhythm::euclid(3, 8, 0).unwrap(), "x..x..x.".parse().unwrap());
+    assert_eq!(Rhythm::euclid(5, 8, 0).unwrap(), "x.xx.xx.".parse().unwrap());
+    assert_eq!(Rhythm::euclid(3, 8, 2).unwrap(), "x.x..x..".parse().unwrap());
+    assert_eq!(Rhythm::euclid(3, 8, -1).unwrap(), "..x..x.x".parse().unwrap());
+    assert_eq!(Rhythm::euclid(4, 4, 0).unwrap(), "xxxx".parse().unwrap());
+    assert_eq!(Rhythm::euclid(0, 4, 0).unwrap(), "....".parse().unwrap());
+}
+
+#[test]
+fn combinations(){
+    let three: Rhythm = "x..".parse().unwrap();
+    let two: Rhythm = "x.".parse().unwrap();
+    assert_eq!(three.union(&two).unwrap(), "x.xxx.".parse().unwrap());
+    assert_eq!(three.intersection(&two).unwrap(), "x.....".parse().unwrap());
+    assert_eq!(three.xor(&two).unwrap(), "..xxx.".parse().unwrap());
+
+    // mixing subdivisions lands everything on a common grid
+    let triplet: Rhythm = "[x.x]".parse().unwrap();
+    assert_eq!(two.union(&triplet).unwrap(), Rhythm::subdivided(2, 3, [0, 2, 3, 5]));
+
+    assert_eq!(three.invert().unwrap(), ".xx".parse().unwrap());
+    assert_eq!(three.rotate(1), ".x.".parse().unwrap());
+    assert_eq!(three.rotate(-1), "..x".parse().unwrap());
+}
+
+#[test]
+fn generated_rhythms_from_json(){
+    let r: Rhythm = serde_json::from_str(r#"{"euclid": [3, 8, 2]}"#).unwrap();
+    assert_eq!(r, Rhythm::euclid(3, 8, 2).unwrap());
+    let r: Rhythm = serde_json::from_str(r#"{"euclid": [3, 8]}"#).unwrap();
+    assert_eq!(r, Rhythm::euclid(3, 8, 0).unwrap());
+    let r: Rhythm = serde_json::from_str(r#"{"xor": ["x..", {"euclid": [1, 2]}]}"#).unwrap();
+    assert_eq!(r, "..xxx.".parse().unwrap());
+    let r: Rhythm = serde_json::from_str(r#"{"rotate": [{"invert": "x.."}, 1]}"#).unwrap();
+    assert_eq!(r, "x.x".parse().unwrap());
+
+    // ones too big to lay out, or with nothing to lay out, are turned down
+    // rather than running out of memory
+    for json in [
+        r#"{"euclid": [1, 10000000000]}"#,
+        r#"{"euclid": [1, 0]}"#,
+        r#"{"union": [{"length": 0, "beats": []}, {"length": 0, "beats": []}]}"#,
+        r#"{"union": [{"length": 10000000000, "beats": [0]}, "x."]}"#,
+        r#"{"xor": [{"length": 4093, "beats": [0]}, {"length": 4091, "beats": [0]}]}"#,
+        r#"{"invert": {"length": 10000000000, "beats": []}}"#,
+    ] {
+        assert!(serde_json::from_str::<Rhythm>(json).is_err(), "{}", json);
+    }
+    assert_eq!(lcm(0, 0), 0);
+}
+
+#[test]
+fn nearest_hits(){
+    let r = Rhythm::new(4, [0, 3]);
+    let (hit, offset) = r.nearest_hit(2.9).unwrap();
+    assert_eq!(hit, 3);
+    assert!((offset + 0.1).abs() < 1e-9);
+    // wrapping round into the next measure
+    let (hit, offset) = r.nearest_hit(7.8).unwrap();
+    assert_eq!(hit, 8);
+    assert!((offset + 0.2).abs() < 1e-9);
+    assert_eq!(Rhythm::new(4, []).nearest_hit(1.0), None);
 }
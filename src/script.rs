@@ -0,0 +1,82 @@
+use raylib::consts::KeyboardKey;
+use crate::inputs::Input;
+use crate::rhythm::Beat;
+use crate::Game;
+
+/// Key presses to play into a game on set beats, for driving it without a window
+#[derive(Debug, Default, Clone)]
+pub struct Script {
+    presses: Vec<(Beat, KeyboardKey)>,
+}
+
+impl Script {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Press `key` on `beat`
+    pub fn press(mut self, beat: Beat, key: KeyboardKey) -> Self {
+        self.presses.push((beat, key));
+        self
+    }
+
+    /// Press `key` on each of `count` beats, starting on `from`
+    pub fn every_beat(mut self, key: KeyboardKey, from: Beat, count: usize) -> Self {
+        self.presses.extend((0..count).map(|i| (from + i as Beat, key)));
+        self
+    }
+
+    /// The presses as inputs, timed on `game`'s clock
+    pub fn inputs(&self, game: &Game) -> Vec<Input> {
+        let tempo = game.clock().tempo();
+        self.presses.iter().map(|(beat, key)| Input::Key(*key, tempo.seconds_at(*beat))).collect()
+    }
+
+    /// Step `game` through to `until`, making the presses as it goes
+    pub fn run(&self, game: &mut Game, until: Beat) {
+        let inputs = self.inputs(game);
+        let until = game.clock().tempo().seconds_at(until);
+        game.run(until, &inputs);
+    }
+}
+
+#[cfg(test)]
+use crate::PlayerState;
+
+#[test]
+fn walking_right_clears_begin(){
+    let mut game = Game::new();
+    game.load_level("maps/begin.json").unwrap();
+    Script::new().every_beat(KeyboardKey::KEY_D, 1.0, 6).run(&mut game, 8.0);
+    let player = game.player().unwrap();
+    assert_eq!(player.position(), (6, 0));
+    assert_eq!(player.state(), PlayerState::Cleared);
+    assert_eq!(player.score().accuracy(), 1.0);
+}
+
+#[test]
+fn vanishing_tiles_kill(){
+    let mut game = Game::new();
+    game.load_level("maps/begin.json").unwrap();
+    // over to the rhythmic column, then onto a tile that's only there every other beat
+    Script::new()
+        .every_beat(KeyboardKey::KEY_S, 1.0, 4)
+        .every_beat(KeyboardKey::KEY_D, 5.0, 2)
+        .run(&mut game, 6.5);
+    assert_eq!(game.player().unwrap().position(), (2, 4));
+    assert_eq!(game.player_state(), Some(PlayerState::Playing));
+    Script::new().run(&mut game, 8.0);
+    assert_eq!(game.player_state(), Some(PlayerState::Died));
+}
+
+#[test]
+fn walking_across_bigmap(){
+    let mut game = Game::new();
+    // bigmap has no goal, which `Level::load` turns down, so play it as it's written
+    let big: crate::Level = serde_json::from_reader(std::fs::File::open("maps/bigmap.json").unwrap()).unwrap();
+    game.play_level(big).unwrap();
+    // so walk off the far side: the last press hits the edge
+    Script::new().every_beat(KeyboardKey::KEY_D, 1.0, 16).run(&mut game, 18.0);
+    assert_eq!(game.player().unwrap().position(), (15, 3));
+    assert_eq!(game.player_state(), Some(PlayerState::Playing));
+}
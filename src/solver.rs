@@ -0,0 +1,270 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use crate::inputs::{Direction, Input};
+use crate::rhythm::{lcm, Beat};
+use crate::state::LevelState;
+use crate::tiles::{PlacedTile, TileKind};
+use crate::{forced_move, step_on, Level};
+
+/// Where the player is, about to act on `beat`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct State {
+    pub position: (usize, usize),
+    pub beat: usize,
+    /// Which way they last went, for ice
+    pub heading: Option<(i32, i32)>,
+    /// Switches, keys, doors and crumbling tiles as they stand, by where they are in `Solver::progress`
+    pub progress: usize,
+}
+
+/// What acting on a beat leads to. Dying isn't an outcome, it's just not a move
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Outcome {
+    Cleared,
+    /// Still standing, about to act on the next beat
+    Alive(State),
+}
+
+/// A way to clear a level
+#[derive(Debug, Clone, PartialEq)]
+pub struct Solution {
+    /// The beat the goal is reached on
+    pub beats: usize,
+    /// Each move and the beat to make it on; every other beat is spent waiting
+    pub moves: Vec<(usize, Direction)>,
+}
+
+impl Solution {
+    /// The moves as key presses, timed on the level's clock
+    pub fn inputs(&self, level: &Level) -> Vec<Input> {
+        self.moves.iter()
+            .map(|(beat, direction)| Input::Key(direction.key(), level.tempo.seconds_at(*beat as Beat)))
+            .collect()
+    }
+}
+
+impl fmt::Display for Solution {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "cleared on beat {} in {} moves", self.beats, self.moves.len())?;
+        for (beat, direction) in &self.moves {
+            writeln!(f, "  beat {:>3}: {:?}", beat, direction)?;
+        }
+        Ok(())
+    }
+}
+
+/// Searches the ways through a level, moving on the beat the way the player does.
+/// Moves are taken as landing exactly on the beat, with no judgement windows
+pub struct Solver<'a> {
+    level: &'a Level,
+    /// Beat after which the tempo stops changing
+    settled: usize,
+    /// Beats for every tile's rhythm to come back round together
+    period: usize,
+    /// whether each cell can be stood on through each beat, switched on or not, and used up or not
+    safe: HashMap<((usize, usize), usize, bool, bool), bool>,
+    /// Every way switches, keys and doors have been found to stand, so states can
+    /// refer to them by number and stay small
+    progress: Vec<LevelState>,
+    seen_progress: HashMap<LevelState, usize>,
+}
+
+impl<'a> Solver<'a> {
+    /// Points per beat to check whether a tile is there
+    pub const SAMPLES: usize = 48;
+
+    pub fn new(level: &'a Level) -> Self {
+        let settled = level.tempo.changes().iter().map(|c| c.beat.ceil() as usize).max().unwrap_or(0);
+        let period = level.tiles.iter()
+            .filter_map(|t| t.rhythm.as_ref())
+            .map(|r| r.length.max(1))
+            .fold(1, lcm);
+        let progress = vec![LevelState::default()];
+        let seen_progress = HashMap::from([(LevelState::default(), 0)]);
+        Solver { level, settled, period, safe: HashMap::new(), progress, seen_progress }
+    }
+
+    pub fn start(&self) -> State {
+        State { position: self.level.starting_location, beat: 0, heading: None, progress: 0 }
+    }
+
+    /// Switches, keys, doors and crumbling tiles as they stand in `state`
+    pub fn progress(&self, state: State) -> &LevelState {
+        &self.progress[state.progress]
+    }
+
+    fn track(&mut self, progress: LevelState) -> usize {
+        if let Some(i) = self.seen_progress.get(&progress) {
+            return *i;
+        }
+        self.progress.push(progress.clone());
+        self.seen_progress.insert(progress, self.progress.len() - 1);
+        self.progress.len() - 1
+    }
+
+    /// Beats for every tile's rhythm to come back round together
+    pub fn period(&self) -> usize {
+        self.period
+    }
+
+    /// States that play out the same from here on share a key
+    pub fn key(&self, state: State) -> State {
+        let beat = if state.beat < self.settled {
+            state.beat
+        } else {
+            self.settled + (state.beat - self.settled) % self.period
+        };
+        State { beat, ..state }
+    }
+
+    /// Whether the player can stand on `tile`, at `position`, from `beat` until the next one
+    pub fn safe(&mut self, position: (usize, usize), beat: usize, tile: PlacedTile) -> bool {
+        let key = self.key(State { position, beat, heading: None, progress: 0 });
+        let key = (key.position, key.beat, tile.switched, tile.spent);
+        if let Some(safe) = self.safe.get(&key) {
+            return *safe;
+        }
+        let safe = (0..Solver::SAMPLES)
+            .all(|i| self.standable(&tile, beat as Beat + i as Beat / Solver::SAMPLES as Beat));
+        self.safe.insert(key, safe);
+        safe
+    }
+
+    /// Whether `tile` can be stood on at `at`
+    pub fn standable(&self, tile: &PlacedTile, at: Beat) -> bool {
+        let window = Level::GRACE / self.level.tempo.beat_length_at(at);
+        !tile.deadly(at, window)
+    }
+
+    /// Everything that can come of acting on the beat in `state`: waiting, which leaves
+    /// the player to the tiles, or moving each way that makes a difference. Moves that
+    /// die are left out
+    pub fn moves(&mut self, state: State) -> Vec<(Option<Direction>, Outcome)> {
+        let level = self.level;
+        let tiles = &level.tiles;
+        let beat = state.beat as Beat;
+        let mut moves = vec![];
+        // waiting on a crumbling tile wears it; moving off it doesn't
+        let mut carried = self.progress(state).clone();
+        carried.wear(tiles, Some(state.position));
+        let (carried_to, carried_heading) = forced_move(tiles, &mut carried, state.position, state.heading, beat);
+        let options = std::iter::once(None).chain(Direction::ALL.into_iter().map(Some));
+        for direction in options {
+            let (position, heading, progress) = match direction {
+                Some(d) => {
+                    let mut progress = self.progress(state).clone();
+                    progress.wear(tiles, None);
+                    let to = step_on(tiles, &progress, state.position, d.offset());
+                    // a move into a wall only matters for staying put when the tiles would carry you off
+                    if to == state.position && carried_to == state.position {
+                        continue;
+                    }
+                    if to == state.position {
+                        (to, state.heading, progress)
+                    } else {
+                        (progress.land(tiles, to, d.offset(), beat), Some(d.offset()), progress)
+                    }
+                },
+                None => (carried_to, carried_heading, carried.clone())
+            };
+            let Some(tile) = progress.get(tiles, position.0, position.1) else { continue };
+            if tile.goal {
+                moves.push((direction, Outcome::Cleared));
+            } else if !tile.broken() && self.safe(position, state.beat, tile) {
+                // heading only matters on ice, so forget it elsewhere and keep the states down
+                let heading = heading.filter(|_| tile.kind == TileKind::Ice);
+                let progress = self.track(progress);
+                moves.push((direction, Outcome::Alive(State { position, beat: state.beat + 1, heading, progress })));
+            }
+        }
+        moves
+    }
+
+    /// The quickest way to clear the level, or `None` if it can't be
+    pub fn solve(&mut self) -> Option<Solution> {
+        let start = self.start();
+        if self.level.tiles.get(start.position.0, start.position.1).map(|t| t.goal).unwrap_or(false) {
+            return Some(Solution { beats: 0, moves: vec![] });
+        }
+        // how each state was first reached, to walk back along once the goal is found
+        let mut came_from: HashMap<State, Option<(State, Option<Direction>)>> = HashMap::new();
+        let mut seen = std::collections::HashSet::from([self.key(start)]);
+        came_from.insert(start, None);
+        let mut queue = VecDeque::from([start]);
+        while let Some(state) = queue.pop_front() {
+            for (direction, outcome) in self.moves(state) {
+                match outcome {
+                    Outcome::Cleared => {
+                        let mut moves: Vec<(usize, Direction)> = direction.map(|d| (state.beat, d)).into_iter().collect();
+                        let mut at = state;
+                        while let Some(Some((previous, direction))) = came_from.get(&at) {
+                            if let Some(d) = direction {
+                                moves.push((previous.beat, *d));
+                            }
+                            at = *previous;
+                        }
+                        moves.reverse();
+                        return Some(Solution { beats: state.beat, moves });
+                    },
+                    Outcome::Alive(next) => {
+                        if seen.insert(self.key(next)) {
+                            came_from.insert(next, Some((state, direction)));
+                            queue.push_back(next);
+                        }
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+#[test]
+fn solves_begin(){
+    let level: Level = serde_json::from_reader(std::fs::File::open("maps/begin.json").unwrap()).unwrap();
+    let solution = Solver::new(&level).solve().unwrap();
+    assert_eq!(solution.beats, 5);
+    assert!(solution.moves.iter().all(|(_, d)| *d == Direction::Right));
+
+    // the moves it finds really do clear the level
+    let mut game = crate::Game::new();
+    game.play_level(level).unwrap();
+    let inputs = solution.inputs(game.level().unwrap());
+    // a beat past the goal, to give the last move time to land
+    let until = game.level().unwrap().tempo.seconds_at(solution.beats as Beat + 1.0);
+    game.run(until, &inputs);
+    assert_eq!(game.player_state(), Some(crate::PlayerState::Cleared));
+}
+
+#[test]
+fn unreachable_goals(){
+    use crate::tiles::{column, floor, goal, Tile};
+    use crate::rhythm::Rhythm;
+    // a tile that's never there walls the goal off
+    let gap = Tile { rhythm: Some(Rhythm::new(2, [])), ..floor() };
+    let level = column([floor(), gap, goal()]);
+    assert_eq!(Solver::new(&level).solve(), None);
+
+    // but one that's there every other beat just needs timing
+    let blinking = Tile { rhythm: Some(Rhythm::new(2, [1])), ..floor() };
+    let level = column([floor(), blinking, goal()]);
+    let solution = Solver::new(&level).solve().unwrap();
+    assert_eq!(solution.moves, vec![(1, Direction::Right), (2, Direction::Right)]);
+}
+
+#[test]
+fn portals_skip_walls(){
+    use crate::tiles::{column, floor, goal, kind};
+    let mut level = column([floor(), kind(TileKind::Portal), kind(TileKind::Wall), kind(TileKind::Portal), goal()]);
+    level.tiles.pair_portals((1, 0), (3, 0));
+    let solution = Solver::new(&level).solve().unwrap();
+    assert_eq!(solution.moves, vec![(0, Direction::Right), (1, Direction::Right)]);
+
+    let mut game = crate::Game::new();
+    game.play_level(level).unwrap();
+    let inputs = solution.inputs(game.level().unwrap());
+    game.run(0.25, &inputs);
+    assert_eq!(game.player().unwrap().position(), (3, 0));
+    game.run(1.0, &inputs);
+    assert_eq!(game.player_state(), Some(crate::PlayerState::Cleared));
+}
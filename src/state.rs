@@ -0,0 +1,191 @@
+use std::collections::{BTreeMap, BTreeSet};
+use serde::{Serialize, Deserialize};
+use crate::rhythm::Beat;
+use crate::tiles::{PlacedTile, TileKind, TileMap};
+
+/// Everything about a level that changes as it's played, beyond its rhythms:
+/// which switches are on, which keys and doors have been used, and how worn
+/// the crumbling tiles are.
+/// Saving this along with the clock and the player is enough to pick a level back up
+#[derive(Debug, Default, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct LevelState {
+    /// Groups whose switches are on
+    pub switched: BTreeSet<u32>,
+    /// Keys the player is carrying, by group
+    pub keys: BTreeMap<u32, u32>,
+    /// Cells whose keys have been picked up
+    pub taken: BTreeSet<(usize, usize)>,
+    /// Doors that have been opened with a key
+    pub unlocked: BTreeSet<(usize, usize)>,
+    /// Beats each crumbling cell has been stood on, counting on through the beats
+    /// since it broke. Cells that are as good as new aren't in here
+    pub worn: BTreeMap<(usize, usize), u32>,
+}
+
+impl LevelState {
+    /// The tile at a cell, as things stand
+    pub fn get<'a>(&self, tiles: &'a TileMap, r: usize, c: usize) -> Option<PlacedTile<'a>> {
+        tiles.get(r, c).map(|tile| self.place((r, c), tile, tiles))
+    }
+
+    /// Every cell's tile as things stand, going down each column in turn
+    pub fn cells<'a>(&'a self, tiles: &'a TileMap) -> impl Iterator<Item = ((usize, usize), PlacedTile<'a>)> {
+        tiles.enumerate_column_major().map(move |(at, tile)| (at, self.place(at, tile, tiles)))
+    }
+
+    fn place<'a>(&self, at: (usize, usize), mut tile: PlacedTile<'a>, tiles: &TileMap) -> PlacedTile<'a> {
+        tile.switched = group(tiles, at).is_some_and(|g| self.switched.contains(&g));
+        tile.spent = self.taken.contains(&at) || self.unlocked.contains(&at);
+        tile.worn = self.worn.get(&at).copied().unwrap_or(0);
+        tile
+    }
+
+    /// Whether a move in `direction` can end on `position`: walls and one-way tiles
+    /// as always, and doors that are shut unless there's a key for them
+    pub fn enterable(&self, tiles: &TileMap, position: (usize, usize), direction: (i32, i32)) -> bool {
+        let Some(tile) = self.get(tiles, position.0, position.1) else { return true };
+        match tile.kind {
+            TileKind::Door => !tile.shut() || group(tiles, position).is_some_and(|g| self.keys.contains_key(&g)),
+            _ => tile.enterable(direction),
+        }
+    }
+
+    /// Where stepping onto `position` going `direction` on `beat` leaves the player, after
+    /// going through a portal if it's open, and with whatever's there acted on. A portal
+    /// into somewhere the move couldn't have gone keeps the player on this side, and
+    /// arriving through a portal doesn't set off the one at the other end
+    pub fn land(&mut self, tiles: &TileMap, position: (usize, usize), direction: (i32, i32), beat: Beat) -> (usize, usize) {
+        let open = self.get(tiles, position.0, position.1)
+            .is_some_and(|t| t.kind == TileKind::Portal && t.on(beat, 0.0).unwrap_or(true));
+        let at = match tiles.meta(position.0, position.1).and_then(|m| m.target) {
+            Some(target) if open && tiles.get(target.0, target.1).is_some() && self.enterable(tiles, target, direction) => target,
+            _ => position
+        };
+        self.arrive(tiles, at);
+        at
+    }
+
+    /// A beat of the player's rhythm has gone by, with them stood on `standing` through
+    /// it if they stayed put. That wears the crumbling tile there, and broken tiles
+    /// that come back get a beat closer to it
+    pub fn wear(&mut self, tiles: &TileMap, standing: Option<(usize, usize)>) {
+        let kind = |at: (usize, usize)| tiles.get(at.0, at.1).map(|t| t.kind);
+        self.worn.retain(|at, worn| match kind(*at) {
+            Some(TileKind::Crumbling { beats, respawn: Some(respawn) }) if *worn >= beats => {
+                *worn += 1;
+                *worn < beats + respawn
+            },
+            _ => true
+        });
+        if let Some(at) = standing {
+            if let Some(TileKind::Crumbling { beats, .. }) = kind(at) {
+                let worn = self.worn.entry(at).or_default();
+                *worn = (*worn + 1).min(beats);
+            }
+        }
+    }
+
+    /// Flip switches, pick up keys and use them on doors, for the player arriving at `at`
+    fn arrive(&mut self, tiles: &TileMap, at: (usize, usize)) {
+        let (Some(tile), Some(group)) = (self.get(tiles, at.0, at.1), group(tiles, at)) else { return };
+        match tile.kind {
+            TileKind::Switch => {
+                if self.switched.contains(&group) {
+                    self.switched.remove(&group);
+                } else {
+                    self.switched.insert(group);
+                }
+            },
+            TileKind::Key if !tile.spent => {
+                self.taken.insert(at);
+                *self.keys.entry(group).or_default() += 1;
+            },
+            TileKind::Door if tile.shut() => {
+                let Some(keys) = self.keys.get_mut(&group) else { return };
+                *keys -= 1;
+                if *keys == 0 {
+                    self.keys.remove(&group);
+                }
+                self.unlocked.insert(at);
+            },
+            _ => {}
+        }
+    }
+}
+
+/// Which group a cell is in, if any
+fn group(tiles: &TileMap, at: (usize, usize)) -> Option<u32> {
+    let grouped = tiles.get(at.0, at.1)
+        .is_some_and(|t| matches!(t.kind, TileKind::Switch | TileKind::Key | TileKind::Door));
+    tiles.meta(at.0, at.1).and_then(|m| m.group).or(if grouped { Some(0) } else { None })
+}
+
+#[test]
+fn switches_keys_and_doors(){
+    use crate::rhythm::Rhythm;
+    use crate::tiles::{column, floor, kind, Tile};
+    let gap = Tile { rhythm: Some(Rhythm::new(2, [])), ..floor() };
+    let mut tiles = column([kind(TileKind::Switch), gap, kind(TileKind::Key), kind(TileKind::Door), kind(TileKind::Door)]).tiles;
+    // the switch holds the gap; the key and first door go together, the last door needs another key
+    tiles.meta_mut(0, 0).unwrap().group = Some(2);
+    tiles.meta_mut(1, 0).unwrap().group = Some(2);
+    tiles.meta_mut(4, 0).unwrap().group = Some(1);
+    let mut state = LevelState::default();
+
+    assert!(state.get(&tiles, 1, 0).unwrap().deadly(0.5, 0.0));
+    assert_eq!(state.land(&tiles, (0, 0), (1, 0), 0.0), (0, 0));
+    assert!(state.switched.contains(&2));
+    assert!(!state.get(&tiles, 1, 0).unwrap().deadly(0.5, 0.0));
+
+    assert!(!state.enterable(&tiles, (3, 0), (1, 0)));
+    state.land(&tiles, (2, 0), (1, 0), 1.0);
+    assert_eq!(state.keys.get(&0), Some(&1));
+    assert!(state.enterable(&tiles, (3, 0), (1, 0)));
+    assert!(!state.enterable(&tiles, (4, 0), (1, 0)));
+    state.land(&tiles, (3, 0), (1, 0), 2.0);
+    assert!(state.keys.is_empty());
+    // the key is gone, and the door stays open
+    state.land(&tiles, (2, 0), (-1, 0), 3.0);
+    assert!(state.keys.is_empty());
+    assert!(state.enterable(&tiles, (3, 0), (-1, 0)));
+
+    // save-states keep all of it
+    let saved: LevelState = serde_json::from_str(&serde_json::to_string(&state).unwrap()).unwrap();
+    assert_eq!(saved, state);
+    // and switching back off lets the gap go again
+    state.land(&tiles, (0, 0), (-1, 0), 4.0);
+    assert!(state.get(&tiles, 1, 0).unwrap().deadly(0.5, 0.0));
+
+    // a key whose group is switched on can still be picked up, and switching
+    // a door open then off again shuts it on whoever's in it
+    let mut state = LevelState { switched: BTreeSet::from([0]), ..Default::default() };
+    state.land(&tiles, (2, 0), (1, 0), 0.0);
+    assert_eq!(state.keys.get(&0), Some(&1));
+    assert!(!state.get(&tiles, 3, 0).unwrap().deadly(0.0, 0.0));
+    state.switched.clear();
+    assert!(state.get(&tiles, 3, 0).unwrap().deadly(0.0, 0.0));
+}
+
+#[test]
+fn crumbling_tiles(){
+    use crate::tiles::{column, kind};
+    let tiles = column([kind(TileKind::Crumbling { beats: 2, respawn: Some(2) }), kind(TileKind::Crumbling { beats: 1, respawn: None })]).tiles;
+    let mut state = LevelState::default();
+
+    // only beats stood on count
+    state.wear(&tiles, None);
+    state.wear(&tiles, Some((0, 0)));
+    assert!(!state.get(&tiles, 0, 0).unwrap().broken());
+    state.wear(&tiles, Some((0, 0)));
+    assert!(state.get(&tiles, 0, 0).unwrap().deadly(0.0, 0.0));
+    // and it's back two beats later
+    state.wear(&tiles, Some((1, 0)));
+    assert!(state.get(&tiles, 0, 0).unwrap().broken());
+    state.wear(&tiles, None);
+    assert_eq!(state.get(&tiles, 0, 0).unwrap().worn, 0);
+    // unlike the one that doesn't come back
+    for _ in 0..4 {
+        state.wear(&tiles, None);
+    }
+    assert!(state.get(&tiles, 1, 0).unwrap().broken());
+}
@@ -0,0 +1,225 @@
+use std::io::{self, Write};
+use crate::rhythm::{Rhythm, Sec, Beat};
+use crate::tempo::TempoMap;
+use crate::Level;
+
+/// The drum sounds a track can trigger
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Voice {
+    Kick,
+    Snare,
+    HiHat,
+    Noise,
+}
+
+impl Voice {
+    /// Voices handed out to a level's rhythms, in order
+    const ROTATION: [Voice; 4] = [Voice::Kick, Voice::HiHat, Voice::Snare, Voice::Noise];
+
+    /// The sound `elapsed` seconds after the voice was hit. `noise` is white noise in -1..1
+    fn sample(&self, elapsed: Sec, noise: f64) -> f64 {
+        use std::f64::consts::TAU;
+        match self {
+            Voice::Kick => {
+                // sine that drops from 150Hz to 50Hz
+                let phase = TAU * (50.0 * elapsed + 100.0 / 20.0 * (1.0 - (-20.0 * elapsed).exp()));
+                phase.sin() * (-8.0 * elapsed).exp()
+            },
+            Voice::Snare => {
+                let tone = (TAU * 180.0 * elapsed).sin() * (-30.0 * elapsed).exp();
+                0.4 * tone + 0.6 * noise * (-18.0 * elapsed).exp()
+            },
+            Voice::HiHat => 0.5 * noise * (-60.0 * elapsed).exp(),
+            Voice::Noise => 0.4 * noise * (-6.0 * elapsed).exp(),
+        }
+    }
+}
+
+/// One voice played on a rhythm
+#[derive(Debug, Clone)]
+pub struct Track {
+    pub rhythm: Rhythm,
+    pub voice: Voice,
+    pub gain: f64,
+}
+
+impl Track {
+    /// Song position of the latest hit at or before `beat`
+    fn last_hit(&self, beat: Beat) -> Option<Beat> {
+        if self.rhythm.length == 0 || self.rhythm.beats.is_empty() {
+            return None;
+        }
+        let measure = self.rhythm.length as Beat;
+        let start = beat - self.rhythm.measure_position(beat);
+        let position = beat - start;
+        let latest = |limit: Beat| self.rhythm.beats.iter()
+            .map(|t| self.rhythm.tick_start(*t))
+            .filter(|t| *t <= limit)
+            .max_by(|a, b| a.total_cmp(b));
+        match latest(position) {
+            Some(tick) => Some(start + tick),
+            // nothing yet this measure, so it's the last hit of the one before
+            None => latest(measure).map(|tick| start - measure + tick)
+        }
+    }
+}
+
+/// A little drum machine that plays a level's rhythms
+#[derive(Debug, Clone)]
+pub struct Synth {
+    pub tracks: Vec<Track>,
+    pub tempo: TempoMap,
+    pub sample_rate: u32,
+    /// Bits to crush the output down to, if any
+    pub crush: Option<u32>,
+    /// How hard to drive the output into saturation; 1.0 is clean
+    pub drive: f64,
+    /// Seeds the noise, so renders are repeatable
+    pub seed: u64,
+}
+
+impl Synth {
+    pub const SAMPLE_RATE: u32 = 22050;
+
+    pub fn new(tempo: TempoMap) -> Self {
+        Synth {
+            tracks: vec![],
+            tempo,
+            sample_rate: Synth::SAMPLE_RATE,
+            crush: Some(6),
+            drive: 2.5,
+            seed: 0,
+        }
+    }
+
+    /// One track for each distinct rhythm in the level, cycling through the voices.
+    /// Tiles that only differ in colour or kind share a track; ones with their own
+    /// sound cue get their own
+    pub fn from_level(level: &Level) -> Self {
+        let mut synth = Synth::new(level.tempo.clone());
+        let mut seen = vec![];
+        for tile in level.tiles.iter() {
+            let Some(rhythm) = &tile.rhythm else { continue };
+            if seen.contains(&(rhythm, &tile.sound)) {
+                continue;
+            }
+            seen.push((rhythm, &tile.sound));
+            let voice = Voice::ROTATION[synth.tracks.len() % Voice::ROTATION.len()];
+            synth.tracks.push(Track { rhythm: rhythm.clone(), voice, gain: 0.8 });
+        }
+        synth
+    }
+
+    fn noise(&self, sample: u64) -> f64 {
+        // splitmix64, so any sample can be rendered without the ones before it
+        let mut z = sample.wrapping_add(self.seed).wrapping_mul(0x9E3779B97F4A7C15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^= z >> 31;
+        (z >> 11) as f64 / (1u64 << 52) as f64 - 1.0
+    }
+
+    /// Render samples starting at sample number `start` of the level into `out`, in -1..1
+    pub fn render(&self, start: u64, out: &mut [f32]) {
+        for (i, out) in out.iter_mut().enumerate() {
+            let sample = start + i as u64;
+            let time = sample as Sec / self.sample_rate as Sec;
+            let beat = self.tempo.beat_at(time);
+            let noise = self.noise(sample);
+            let mut mix = 0.0;
+            for track in &self.tracks {
+                if let Some(hit) = track.last_hit(beat) {
+                    let elapsed = time - self.tempo.seconds_at(hit);
+                    mix += track.gain * track.voice.sample(elapsed, noise);
+                }
+            }
+            *out = self.grime(mix) as f32;
+        }
+    }
+
+    /// Distort and bitcrush a sample
+    fn grime(&self, sample: f64) -> f64 {
+        let driven = (sample * self.drive).tanh();
+        match self.crush {
+            Some(bits) => {
+                let levels = (1u64 << bits.clamp(1, 24)) as f64 / 2.0;
+                (driven * levels).round() / levels
+            },
+            None => driven
+        }
+    }
+
+    /// Render the first `seconds` of the level
+    pub fn render_seconds(&self, seconds: Sec) -> Vec<f32> {
+        let mut out = vec![0.0; (seconds * self.sample_rate as Sec) as usize];
+        self.render(0, &mut out);
+        out
+    }
+}
+
+/// Write mono samples in -1..1 as a 16-bit WAV file
+pub fn write_wav<W: Write>(out: &mut W, samples: &[f32], sample_rate: u32) -> io::Result<()> {
+    let data_size = samples.len() as u32 * 2;
+    out.write_all(b"RIFF")?;
+    out.write_all(&(36 + data_size).to_le_bytes())?;
+    out.write_all(b"WAVEfmt ")?;
+    out.write_all(&16u32.to_le_bytes())?;
+    out.write_all(&1u16.to_le_bytes())?; // PCM
+    out.write_all(&1u16.to_le_bytes())?; // mono
+    out.write_all(&sample_rate.to_le_bytes())?;
+    out.write_all(&(sample_rate * 2).to_le_bytes())?;
+    out.write_all(&2u16.to_le_bytes())?;
+    out.write_all(&16u16.to_le_bytes())?;
+    out.write_all(b"data")?;
+    out.write_all(&data_size.to_le_bytes())?;
+    for s in samples {
+        out.write_all(&((s.clamp(-1.0, 1.0) * i16::MAX as f32) as i16).to_le_bytes())?;
+    }
+    Ok(())
+}
+
+#[test]
+fn renders_hits_on_the_beat(){
+    let mut synth = Synth::new(TempoMap::constant(120.0));
+    synth.tracks.push(Track { rhythm: "x...".parse().unwrap(), voice: Voice::Kick, gain: 1.0 });
+    let samples = synth.render_seconds(2.0);
+    let rate = synth.sample_rate as usize;
+    let loudness = |from: Sec, to: Sec| samples[(from * rate as Sec) as usize..(to * rate as Sec) as usize]
+        .iter().map(|s| s.abs()).fold(0.0, f32::max);
+    // a kick on beat 0 of each 4-beat (2 second) measure, ringing out before the next
+    assert!(loudness(0.0, 0.1) > 0.5);
+    assert!(loudness(1.5, 2.0) < 0.05);
+
+    // rendering in chunks gives the same samples as all at once
+    let mut chunk = vec![0.0; 100];
+    synth.render(5000, &mut chunk);
+    assert_eq!(&chunk[..], &samples[5000..5100]);
+}
+
+#[test]
+fn one_track_per_rhythm(){
+    use crate::tiles::Tile;
+    use raylib::prelude::Color;
+    let red = Tile::from(&Color::RED, Some(Rhythm::new(2, [0])));
+    let blue = Tile::from(&Color::BLUE, Some(Rhythm::new(2, [0])));
+    let other = Tile::from(&Color::BLUE, Some(Rhythm::new(2, [1])));
+    let level = Level::new(array2d::Array2D::from_rows(&[vec![red, blue, other]]).unwrap(), (0, 0), 120.0);
+    assert_eq!(level.tiles.iter().count(), 3);
+    let synth = Synth::from_level(&level);
+    let rhythms: Vec<&Rhythm> = synth.tracks.iter().map(|t| &t.rhythm).collect();
+    assert_eq!(rhythms, [&Rhythm::new(2, [0]), &Rhythm::new(2, [1])]);
+}
+
+#[test]
+fn writes_wav(){
+    let mut synth = Synth::new(TempoMap::constant(140.0));
+    synth.tracks.push(Track { rhythm: Rhythm::euclid(3, 8, 0).unwrap(), voice: Voice::Snare, gain: 1.0 });
+    synth.tracks.push(Track { rhythm: "[xx]".parse().unwrap(), voice: Voice::HiHat, gain: 1.0 });
+    let samples = synth.render_seconds(0.5);
+    let mut wav = vec![];
+    write_wav(&mut wav, &samples, synth.sample_rate).unwrap();
+    assert_eq!(&wav[0..4], b"RIFF");
+    assert_eq!(&wav[8..12], b"WAVE");
+    assert_eq!(wav.len(), 44 + 2 * samples.len());
+    assert!(samples.iter().any(|s| s.abs() > 0.1));
+}
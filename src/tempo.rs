@@ -0,0 +1,327 @@
+use serde::{Serialize,Deserialize};
+use crate::rhythm::{Sec, BPM, Beat};
+use crate::validation::Problem;
+
+/// A time signature: `(beats per bar, note value)`, so 7/8 is `(7, 8)`
+pub type Meter = (u32, u32);
+
+/// Set the tempo from `beat` onwards.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TempoChange {
+    pub beat: Beat,
+    pub bpm: BPM,
+    /// Ramp linearly from the previous change up to this one
+    /// instead of jumping at `beat`
+    #[serde(default)]
+    pub ramp: bool,
+}
+
+/// Switch time signature from `beat` onwards. Should land on a barline.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct MeterChange {
+    pub beat: Beat,
+    pub meter: Meter,
+}
+
+/// How a tempo map looks in level JSON: either a plain BPM, or lists of changes
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+enum TempoRepr {
+    Constant(BPM),
+    Map {
+        changes: Vec<TempoChange>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        meters: Vec<MeterChange>,
+    }
+}
+
+/// Tempo and time signature over the course of a level.
+/// Converts between song position in seconds and in beats.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(from = "TempoRepr", into = "TempoRepr")]
+pub struct TempoMap {
+    changes: Vec<TempoChange>,
+    meters: Vec<MeterChange>,
+    /// when each change starts, in seconds
+    starts: Vec<Sec>,
+}
+
+impl Default for TempoMap {
+    fn default() -> Self {
+        TempoMap::constant(120.0)
+    }
+}
+
+impl From<BPM> for TempoMap {
+    fn from(tempo: BPM) -> Self {
+        TempoMap::constant(tempo)
+    }
+}
+
+impl From<TempoRepr> for TempoMap {
+    fn from(repr: TempoRepr) -> Self {
+        match repr {
+            TempoRepr::Constant(bpm) => TempoMap::constant(bpm),
+            TempoRepr::Map { changes, meters } => TempoMap::new(changes, meters)
+        }
+    }
+}
+
+impl From<TempoMap> for TempoRepr {
+    fn from(map: TempoMap) -> Self {
+        let only = map.changes[0];
+        if map.changes.len() == 1 && only.beat == 0.0 && !only.ramp && map.meters.is_empty() {
+            TempoRepr::Constant(only.bpm)
+        } else {
+            TempoRepr::Map { changes: map.changes, meters: map.meters }
+        }
+    }
+}
+
+impl TempoMap {
+    pub fn constant(tempo: BPM) -> Self {
+        TempoMap::new(vec![TempoChange{beat: 0.0, bpm: tempo, ramp: false}], vec![])
+    }
+
+    /// Changes of both kinds have to come in beat order, each after the last, and the
+    /// first tempo change has to be a plain one at beat 0. They're kept as they're
+    /// given so `problems` can point at the ones that aren't
+    pub fn new(mut changes: Vec<TempoChange>, meters: Vec<MeterChange>) -> Self {
+        if changes.is_empty() {
+            changes.push(TempoChange{beat: 0.0, bpm: 120.0, ramp: false});
+        }
+        // a first change that comes late is read as having been in effect from the start
+        let mut starts = vec![60.0 * changes[0].beat / changes[0].bpm];
+        for i in 1..changes.len() {
+            let start = starts[i - 1] + segment_time(&changes[i - 1], Some(&changes[i]), changes[i].beat);
+            starts.push(start);
+        }
+        TempoMap { changes, meters, starts }
+    }
+
+    pub fn changes(&self) -> &[TempoChange] {
+        &self.changes
+    }
+
+    pub fn meters(&self) -> &[MeterChange] {
+        &self.meters
+    }
+
+    /// Tempos that can't be played, meters that can't be counted, and changes
+    /// that don't come after the one before
+    pub fn problems(&self) -> Vec<Problem> {
+        let mut problems = vec![];
+        let constant = self.changes.len() == 1 && self.meters.is_empty();
+        for (i, change) in self.changes.iter().enumerate() {
+            if !(change.bpm.is_finite() && change.bpm > 0.0) {
+                let path = if constant { "$.tempo".to_string() } else { format!("$.tempo.changes[{}].bpm", i) };
+                problems.push(Problem::new(path, format!("{} isn't a tempo that can be played", change.bpm)));
+            }
+        }
+        let first = self.changes[0];
+        if first.beat != 0.0 {
+            problems.push(Problem::new("$.tempo.changes[0].beat", format!(
+                "the first change sets the tempo from the start, so it has to be at beat 0, not {}", first.beat)));
+        }
+        if first.ramp {
+            problems.push(Problem::new("$.tempo.changes[0].ramp", "the first change has nothing to ramp from"));
+        }
+        for (i, meter) in self.meters.iter().enumerate() {
+            if meter.meter.0 == 0 || meter.meter.1 == 0 {
+                problems.push(Problem::new(format!("$.tempo.meters[{}].meter", i), "a bar needs at least one beat, of a note value above 0"));
+            }
+        }
+        problems.extend(out_of_order(self.changes.iter().map(|c| c.beat), "changes"));
+        problems.extend(out_of_order(self.meters.iter().map(|m| m.beat), "meters"));
+        problems
+    }
+
+    /// index of the change in effect at `beat`
+    fn segment_at_beat(&self, beat: Beat) -> usize {
+        self.changes.iter().rposition(|c| c.beat <= beat).unwrap_or(0)
+    }
+
+    fn segment_at_time(&self, time: Sec) -> usize {
+        self.starts.iter().rposition(|s| *s <= time).unwrap_or(0)
+    }
+
+    /// Tempo at `beat`, following any ramp in progress
+    pub fn tempo_at(&self, beat: Beat) -> BPM {
+        let i = self.segment_at_beat(beat);
+        let change = &self.changes[i];
+        match self.changes.get(i + 1).and_then(|next| ramp_slope(change, next)) {
+            Some(slope) => change.bpm + slope * (beat - change.beat),
+            None => change.bpm
+        }
+    }
+
+    /// Length of a beat at `beat`, in seconds
+    pub fn beat_length_at(&self, beat: Beat) -> Sec {
+        60.0 / self.tempo_at(beat)
+    }
+
+    /// Seconds from the start of the level to `beat`
+    pub fn seconds_at(&self, beat: Beat) -> Sec {
+        let i = self.segment_at_beat(beat);
+        self.starts[i] + segment_time(&self.changes[i], self.changes.get(i + 1), beat)
+    }
+
+    /// Song position in beats at `time` seconds into the level
+    pub fn beat_at(&self, time: Sec) -> Beat {
+        let i = self.segment_at_time(time);
+        let change = &self.changes[i];
+        let elapsed = time - self.starts[i];
+        match self.changes.get(i + 1).and_then(|next| ramp_slope(change, next)) {
+            Some(slope) => change.beat + change.bpm * ((slope * elapsed / 60.0).exp() - 1.0) / slope,
+            None => change.beat + elapsed * change.bpm / 60.0
+        }
+    }
+
+    /// Time signature in effect at `beat`; 4/4 if the map doesn't say
+    pub fn meter_at(&self, beat: Beat) -> Meter {
+        self.meters.iter().rev()
+            .find(|m| m.beat <= beat)
+            .map(|m| m.meter)
+            .unwrap_or((4, 4))
+    }
+
+    /// Which bar `beat` falls in, and how many beats into that bar it is
+    pub fn bar_at(&self, beat: Beat) -> (usize, Beat) {
+        let mut bar = 0;
+        let mut start = 0.0;
+        let mut meter = (4, 4);
+        for change in self.meters.iter().take_while(|m| m.beat <= beat) {
+            bar += ((change.beat - start) / bar_length(meter)).round() as usize;
+            start = change.beat;
+            meter = change.meter;
+        }
+        let into = beat - start;
+        let length = bar_length(meter);
+        (bar + (into / length).floor() as usize, into.rem_euclid(length))
+    }
+}
+
+/// Length of a bar in beats, counting a beat as a quarter note
+pub fn bar_length(meter: Meter) -> Beat {
+    meter.0 as Beat * 4.0 / meter.1 as Beat
+}
+
+/// Problems for each of `beats` that doesn't come after the one before, in the `list` of changes
+fn out_of_order(beats: impl Iterator<Item = Beat>, list: &str) -> Vec<Problem> {
+    let beats: Vec<Beat> = beats.collect();
+    beats.windows(2).enumerate()
+        .filter(|(_, pair)| pair[1].partial_cmp(&pair[0]) != Some(std::cmp::Ordering::Greater))
+        .map(|(i, pair)| Problem::new(format!("$.tempo.{}[{}].beat", list, i + 1),
+            format!("beat {} has to come after the change before it, at beat {}", pair[1], pair[0])))
+        .collect()
+}
+
+/// Seconds from the start of `change` to `beat`, ramping towards `next` if it asks for one
+fn segment_time(change: &TempoChange, next: Option<&TempoChange>, beat: Beat) -> Sec {
+    let beats = beat - change.beat;
+    match next.and_then(|next| ramp_slope(change, next)) {
+        Some(slope) => {
+            let tempo = change.bpm + slope * beats;
+            60.0 / slope * (tempo / change.bpm).ln()
+        }
+        None => 60.0 * beats / change.bpm
+    }
+}
+
+/// BPM gained per beat ramping from `change` to `next`, if `next` ramps at all.
+/// A ramp over no beats is taken as a jump, so it can't divide by zero
+fn ramp_slope(change: &TempoChange, next: &TempoChange) -> Option<BPM> {
+    (next.ramp && next.bpm != change.bpm && next.beat > change.beat)
+        .then(|| (next.bpm - change.bpm) / (next.beat - change.beat))
+}
+
+#[test]
+fn constant_tempo(){
+    let map = TempoMap::constant(120.0);
+    assert!((map.seconds_at(4.0) - 2.0).abs() < 1e-9);
+    assert!((map.beat_at(2.0) - 4.0).abs() < 1e-9);
+    assert!((map.beat_length_at(100.0) - 0.5).abs() < 1e-9);
+}
+
+#[test]
+fn tempo_changes(){
+    let map = TempoMap::new(vec![
+        TempoChange{beat: 0.0, bpm: 60.0, ramp: false},
+        TempoChange{beat: 4.0, bpm: 120.0, ramp: false},
+        TempoChange{beat: 8.0, bpm: 120.0, ramp: false},
+        TempoChange{beat: 12.0, bpm: 240.0, ramp: true},
+    ], vec![]);
+    // 4 beats at 60, then a jump to 120
+    assert!((map.seconds_at(4.0) - 4.0).abs() < 1e-9);
+    assert!((map.seconds_at(5.0) - 4.5).abs() < 1e-9);
+    // ramping from 120 to 240 is quicker than 4 beats at 120, slower than at 240
+    let ramp = map.seconds_at(12.0) - map.seconds_at(8.0);
+    assert!(ramp < 2.0 && ramp > 1.0);
+    assert!((map.tempo_at(10.0) - 180.0).abs() < 1e-9);
+    assert!((map.tempo_at(20.0) - 240.0).abs() < 1e-9);
+    for beat in [0.5, 3.9, 4.0, 6.3, 9.5, 11.99, 16.0] {
+        assert!((map.beat_at(map.seconds_at(beat)) - beat).abs() < 1e-9);
+    }
+}
+
+#[test]
+fn meters(){
+    let map = TempoMap::new(vec![], vec![
+        MeterChange{beat: 8.0, meter: (7, 8)},
+    ]);
+    assert_eq!(map.meter_at(2.0), (4, 4));
+    assert_eq!(map.meter_at(9.0), (7, 8));
+    assert_eq!(map.bar_at(5.0), (1, 1.0));
+    assert_eq!(map.bar_at(8.0), (2, 0.0));
+    assert_eq!(map.bar_at(12.0), (3, 0.5));
+    assert!(map.problems().is_empty());
+
+    // they have to come in order too
+    let map = TempoMap::new(vec![], vec![
+        MeterChange{beat: 8.0, meter: (7, 8)},
+        MeterChange{beat: 4.0, meter: (3, 4)},
+    ]);
+    let paths: Vec<String> = map.problems().into_iter().map(|p| p.path).collect();
+    assert_eq!(paths, ["$.tempo.meters[1].beat"]);
+}
+
+#[test]
+fn tempo_json(){
+    let map: TempoMap = serde_json::from_str("120.0").unwrap();
+    assert_eq!(map, TempoMap::constant(120.0));
+    assert_eq!(serde_json::to_string(&map).unwrap(), "120.0");
+
+    let map: TempoMap = serde_json::from_str(
+        r#"{"changes": [{"beat": 0, "bpm": 100}, {"beat": 16, "bpm": 140, "ramp": true}],
+            "meters": [{"beat": 0, "meter": [7, 8]}]}"#).unwrap();
+    assert_eq!(map.changes().len(), 2);
+    assert_eq!(map.meter_at(3.0), (7, 8));
+    let round_trip: TempoMap = serde_json::from_str(&serde_json::to_string(&map).unwrap()).unwrap();
+    assert_eq!(map, round_trip);
+
+    // a first change that isn't a plain one at beat 0 is kept as written, and reported
+    let json = r#"{"changes":[{"beat":2.0,"bpm":90.0,"ramp":true}]}"#;
+    let map: TempoMap = serde_json::from_str(json).unwrap();
+    assert_eq!(serde_json::to_string(&map).unwrap(), json);
+    let paths: Vec<String> = map.problems().into_iter().map(|p| p.path).collect();
+    assert_eq!(paths, ["$.tempo.changes[0].beat", "$.tempo.changes[0].ramp"]);
+    assert!(map.seconds_at(0.0).abs() < 1e-9);
+    assert!((map.beat_at(map.seconds_at(3.0)) - 3.0).abs() < 1e-9);
+}
+
+#[test]
+fn ramps_over_no_beats_jump(){
+    let map = TempoMap::new(vec![
+        TempoChange{beat: 0.0, bpm: 60.0, ramp: false},
+        TempoChange{beat: 4.0, bpm: 90.0, ramp: false},
+        TempoChange{beat: 4.0, bpm: 120.0, ramp: true},
+    ], vec![]);
+    for beat in [2.0, 4.0, 6.0] {
+        assert!(map.seconds_at(beat).is_finite());
+        assert!((map.beat_at(map.seconds_at(beat)) - beat).abs() < 1e-9);
+    }
+    assert_eq!(map.tempo_at(6.0), 120.0);
+    // but levels that do this get told
+    let paths: Vec<String> = map.problems().into_iter().map(|p| p.path).collect();
+    assert_eq!(paths, ["$.tempo.changes[2].beat"]);
+}
@@ -1,17 +1,83 @@
 use crate::rhythm::*;
-use std::{collections::HashSet,hash::Hash};
+use crate::audio::SoundCue;
+use crate::inputs::Direction;
+use std::{hash::Hash,ops::Deref};
 use raylib::prelude::*;
 use serde::*;
 use array2d::Array2D;
+use crate::validation::Problem;
 
 
+/// What a tile does to the player, on top of coming and going with its rhythm
+#[derive(Debug, Default, PartialEq, Eq, Hash, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TileKind {
+    #[default]
+    Floor,
+    /// Can't be walked onto; moves into it go nowhere
+    Wall,
+    /// Kill whoever is standing on them while they're up. Spikes with a rhythm
+    /// are up on its ticks, and the rest of the time they're safe to stand on
+    Spikes,
+    /// Can only be walked onto moving the way it points
+    OneWay(Direction),
+    /// Falling in kills, whatever the rhythm
+    Pit,
+    /// Pushes whoever is standing on it a cell the way it points, on each beat
+    /// its rhythm is on, or every beat without one
+    Conveyor(Direction),
+    /// Whoever is on it when a beat starts slides on the way they were going,
+    /// until they're off the ice or run into something
+    Ice,
+    /// Launches whoever is standing on it two cells the way it points, over
+    /// whatever's in between, on the beats its rhythm is on
+    Spring(Direction),
+    /// Sends whoever steps onto it to the cell's `target`, on the beats its rhythm is on
+    Portal,
+    /// Stepping onto it switches the cell's group on, or back off again. Switched on,
+    /// tiles that come and go stay put, ones that keep time stop, and doors open
+    Switch,
+    /// Picked up by stepping onto it, and good for opening one door of the same group
+    Key,
+    /// A wall until it's opened, by a switch or a key of its group
+    Door,
+    /// Breaks once it's been stood on through `beats` of the player's beats, leaving
+    /// a hole that kills like a pit. With `respawn`, it comes back that many beats later
+    Crumbling {
+        beats: u32,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        respawn: Option<u32>,
+    },
+}
+
+/// Things about one cell of the map that the tile it shares with other cells can't say
+#[derive(Debug, Default, PartialEq, Eq, Clone, Serialize, Deserialize)]
+pub struct CellMeta {
+    /// Where a portal in this cell leads, as (row, column)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<(usize, usize)>,
+    /// Which switches, keys and doors go together, and which switch holds this cell.
+    /// Switches, keys and doors without one are in group 0
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub group: Option<u32>,
+}
+
 #[derive(Debug, Default, PartialEq, Clone,Serialize,Deserialize)]
 pub struct Tile {
     pub color: Color, 
     pub rhythm: Option<Rhythm>,
     #[serde(default = "default_goal")]
-    pub goal: bool
-    // todo: add more features?
+    pub goal: bool,
+    /// Played when the tile's rhythm turns it on or off
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sound: Option<SoundCue>,
+    /// Floor if left out
+    #[serde(default, skip_serializing_if = "is_floor")]
+    pub kind: TileKind,
+}
+
+fn is_floor(kind: &TileKind) -> bool {
+    *kind == TileKind::Floor
 }
 
 fn default_goal()->bool{
@@ -24,6 +90,7 @@ impl Hash for Tile {
     fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
         self.color.color_to_int().hash(state);
         self.rhythm.hash(state);
+        self.kind.hash(state);
     }
 }
 
@@ -35,23 +102,60 @@ impl Tile {
             ..Default::default()
         }
     }
-    pub fn update(&mut self, delta: Sec){
-        if let Some(rhythm) = &mut self.rhythm  {
-            rhythm.update(delta)
+    /// Whether the tile is solid at song position `beat`, give or take `window` beats.
+    /// `None` for tiles without a rhythm, which are always there
+    pub fn on(&self, beat: Beat, window: Beat) -> Option<bool> {
+        self.rhythm.as_ref().map(|r|{
+            r.on_beat_at(beat, window)
+        })
+    }
+
+    /// Whether the tile's rhythm says when it acts, rather than when it's there
+    pub fn acts_on_rhythm(&self) -> bool {
+        matches!(self.kind, TileKind::Spikes | TileKind::Conveyor(_) | TileKind::Spring(_) | TileKind::Portal)
+    }
+
+    /// Whether standing on the tile at song position `beat` kills. Tiles that come and go
+    /// can be stood on within `window` beats of their ticks; spikes get no such leeway
+    pub fn deadly(&self, beat: Beat, window: Beat) -> bool {
+        match self.kind {
+            TileKind::Pit => true,
+            TileKind::Spikes => self.on(beat, 0.0).unwrap_or(true),
+            _ if self.acts_on_rhythm() => false,
+            _ => !self.on(beat, window).unwrap_or(true),
+        }
+    }
+
+    /// Whether a move in `direction` can end on this tile
+    pub fn enterable(&self, direction: (i32, i32)) -> bool {
+        match self.kind {
+            TileKind::Wall => false,
+            TileKind::OneWay(way) => way.offset() == (direction.0.signum(), direction.1.signum()),
+            _ => true,
         }
     }
 
-    pub fn on(&self, window_size: Sec) -> Option<bool> {
-        self.rhythm.as_ref().map(|r|{
-            r.on_beat(window_size)
-        })
+    /// How far the tile moves whoever is on it as `beat` starts, if at all,
+    /// when they've been going `heading`
+    pub fn push(&self, beat: Beat, heading: Option<(i32, i32)>) -> Option<(i32, i32)> {
+        let on = self.on(beat, 0.0).unwrap_or(true);
+        match self.kind {
+            TileKind::Conveyor(way) if on => Some(way.offset()),
+            TileKind::Spring(way) if on => Some((2 * way.offset().0, 2 * way.offset().1)),
+            TileKind::Ice => heading,
+            _ => None
+        }
     }
 
-    pub fn get_color(&self) -> Color {
+    pub fn get_color(&self, beat: Beat) -> Color {
+        if self.acts_on_rhythm() {
+            // these are always there, it's what they do that keeps time
+            return self.color;
+        }
         match &self.rhythm {
             None => self.color,
             Some(tile_rhythm) => {
-                if tile_rhythm.on() {
+                if tile_rhythm.on_at(beat) {
                     self.color
                 } else {
                     Color::new(0, 0, 0, 0)
@@ -63,31 +167,120 @@ impl Tile {
 
 #[test]
 fn test_tile_on(){
-    let rhyth = Rhythm::new(2, 60.0, [0]);
-    let mut t = Tile{
+    let rhyth = Rhythm::new(2, [0]);
+    let t = Tile{
         color: Color::WHITE,
         rhythm: Some(rhyth),
-        goal: false
+        ..Default::default()
     };
-    assert!(t.on(0.015).unwrap());
-    t.rhythm.as_mut().map(|r| r.update(1.005));
-    assert!(t.on(0.015).unwrap());
-    t.rhythm.as_mut().map(|r| r.update(0.015));
-    assert!(!t.on(0.015).unwrap())
+    let clock = Conductor::new(60.0);
+    let window = clock.to_beats(0.015);
+    assert!(t.on(0.0, window).unwrap());
+    assert!(t.on(1.005, window).unwrap());
+    assert!(!t.on(1.02, window).unwrap())
+
 
+}
 
+/// A tile as it sits at one spot on the map, with that cell's phase offset applied
+#[derive(Debug, Clone, Copy)]
+pub struct PlacedTile<'a> {
+    pub tile: &'a Tile,
+    /// How many beats late this cell's rhythm runs
+    pub phase: Beat,
+    /// Its group's switch is on
+    pub switched: bool,
+    /// Used up: a key that's been picked up, or a door that's been unlocked with one
+    pub spent: bool,
+    /// For crumbling tiles, beats it's been stood on, then beats since it broke
+    pub worn: u32,
+}
+
+impl Deref for PlacedTile<'_> {
+    type Target = Tile;
+
+    fn deref(&self) -> &Tile {
+        self.tile
+    }
+}
+
+impl PlacedTile<'_> {
+    /// Whether this is a crumbling tile that's been stood on until it broke
+    pub fn broken(&self) -> bool {
+        matches!(self.kind, TileKind::Crumbling { beats, .. } if self.worn >= beats)
+    }
+
+    /// Whether this is a door that neither a switch nor a key has opened
+    pub fn shut(&self) -> bool {
+        self.kind == TileKind::Door && !self.switched && !self.spent
+    }
+
+    pub fn on(&self, beat: Beat, window: Beat) -> Option<bool> {
+        if self.switched && (self.rhythm.is_some() || self.acts_on_rhythm()) {
+            // switched tiles that come and go stay, and ones that keep time stop
+            return Some(!self.acts_on_rhythm());
+        }
+        self.tile.on(beat - self.phase, window)
+    }
+
+    pub fn deadly(&self, beat: Beat, window: Beat) -> bool {
+        // a door that shuts on whoever's in it crushes them
+        if self.broken() || self.shut() {
+            return true;
+        }
+        if self.switched {
+            return self.kind == TileKind::Pit;
+        }
+        self.tile.deadly(beat - self.phase, window)
+    }
+
+    pub fn push(&self, beat: Beat, heading: Option<(i32, i32)>) -> Option<(i32, i32)> {
+        if self.switched && self.acts_on_rhythm() {
+            return None;
+        }
+        self.tile.push(beat - self.phase, heading)
+    }
+
+    pub fn get_color(&self, beat: Beat) -> Color {
+        // crumbling tiles fade as they wear, and are gone once they break
+        if let TileKind::Crumbling { beats, .. } = self.kind {
+            if self.broken() {
+                return Color::new(0, 0, 0, 0);
+            }
+            let mut color = self.tile.get_color(beat - self.phase);
+            let left = 1.0 - self.worn as f32 / beats as f32;
+            color.a = (color.a as f32 * (0.25 + 0.75 * left)) as u8;
+            return color;
+        }
+        if self.switched {
+            return self.color;
+        }
+        self.tile.get_color(beat - self.phase)
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct TileMap{
     tiles: Vec<Tile>,
     map: Array2D<usize>,
+    /// Per-cell phase offsets in beats, so cells sharing a tile can be staggered.
+    /// Missing means every cell is in phase
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    phase: Option<Array2D<Beat>>,
+    /// Per-cell data, like where each portal goes. Missing means no cell has any
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    meta: Option<Array2D<CellMeta>>,
 }
 
 impl From<&Array2D<Tile>>for TileMap {
     fn from(tiles: &Array2D<Tile>) -> Self {
-        let tileset: HashSet<_> = tiles.elements_row_major_iter().collect();
-        let tiles_with_indices: Vec<_> = tileset.into_iter().collect();
+        // distinct tiles in the order they first appear, so the same grid always packs the same way
+        let mut tiles_with_indices: Vec<&Tile> = vec![];
+        for tile in tiles.elements_row_major_iter() {
+            if !tiles_with_indices.contains(&tile) {
+                tiles_with_indices.push(tile);
+            }
+        }
         let tilemap = Array2D::from_iter_row_major(tiles.elements_row_major_iter().map(
             |t | {
                 for (i,t1) in tiles_with_indices.iter().enumerate(){
@@ -104,6 +297,8 @@ impl From<&Array2D<Tile>>for TileMap {
         Self {
             tiles: tiles_with_indices.into_iter().cloned().collect(),
             map: tilemap,
+            phase: None,
+            meta: None,
         }
     }
 }
@@ -111,9 +306,9 @@ impl From<&Array2D<Tile>>for TileMap {
 
 impl TileMap{
 
-    pub fn enumerate_column_major(&self) -> impl Iterator<Item=((usize,usize),&Tile)>{
+    pub fn enumerate_column_major(&self) -> impl Iterator<Item=((usize,usize),PlacedTile<'_>)>{
         self.map.enumerate_column_major().map(
-            |((r,c),idx)| ((r,c), &self.tiles[*idx])
+            |((r,c),idx)| ((r,c), PlacedTile{tile: &self.tiles[*idx], phase: self.phase(r,c), switched: false, spent: false, worn: 0})
         )
     }
 
@@ -121,9 +316,41 @@ impl TileMap{
         self.map.indices_column_major()
     }
 
-    pub fn get(&self, r: usize, c:usize) -> Option<&Tile>{
+    pub fn get(&self, r: usize, c:usize) -> Option<PlacedTile<'_>>{
         let idx = self.map.get(r,c);
-        idx.map(|i| &self.tiles[*i])
+        idx.map(|i| PlacedTile{tile: &self.tiles[*i], phase: self.phase(r,c), switched: false, spent: false, worn: 0})
+    }
+
+    /// Phase offset of a cell, in beats
+    pub fn phase(&self, r: usize, c: usize) -> Beat {
+        self.phase.as_ref().and_then(|p| p.get(r,c)).copied().unwrap_or(0.0)
+    }
+
+    pub fn set_phase(&mut self, r: usize, c: usize, phase: Beat) {
+        let (rows, columns) = (self.num_rows(), self.num_columns());
+        let phases = self.phase.get_or_insert_with(|| Array2D::filled_with(0.0, rows, columns));
+        if let Some(p) = phases.get_mut(r,c) {
+            *p = phase;
+        }
+    }
+
+    /// What's known about a cell beyond its tile
+    pub fn meta(&self, r: usize, c: usize) -> Option<&CellMeta> {
+        self.meta.as_ref().and_then(|m| m.get(r,c))
+    }
+
+    pub fn meta_mut(&mut self, r: usize, c: usize) -> Option<&mut CellMeta> {
+        let (rows, columns) = (self.num_rows(), self.num_columns());
+        self.meta.get_or_insert_with(|| Array2D::filled_with(CellMeta::default(), rows, columns)).get_mut(r,c)
+    }
+
+    /// Link the portals at `a` and `b` to each other
+    pub fn pair_portals(&mut self, a: (usize, usize), b: (usize, usize)) {
+        for (from, to) in [(a, b), (b, a)] {
+            if let Some(meta) = self.meta_mut(from.0, from.1) {
+                meta.target = Some(to);
+            }
+        }
     }
 
     pub fn get_mut(&mut self, r: usize, c: usize) -> Option<&mut Tile>{
@@ -148,4 +375,138 @@ impl TileMap{
         self.map.num_columns()
     }
 
-}
\ No newline at end of file
+    /// Everything wrong with the tiles and the grid, as found in a level's JSON.
+    /// Nothing else about the map can be trusted until this comes back empty
+    pub fn problems(&self) -> Vec<Problem> {
+        let mut problems = vec![];
+        if self.tiles.is_empty() {
+            problems.push(Problem::new("$.tiles", "there has to be at least one tile"));
+        }
+        for (i, tile) in self.tiles.iter().enumerate() {
+            if let Some(rhythm) = &tile.rhythm {
+                problems.extend(rhythm.problems(&format!("$.tiles[{}].rhythm", i)));
+            }
+            if let TileKind::Crumbling { beats: 0, .. } = tile.kind {
+                problems.push(Problem::new(format!("$.tiles[{}].kind.crumbling.beats", i),
+                    "a crumbling tile has to last at least a beat"));
+            }
+        }
+        let cells = self.map.elements_row_major_iter().count();
+        if cells != self.map.num_elements() {
+            problems.push(Problem::new("$.map.array", format!(
+                "{} rows of {} cells need {} entries, but there are {}",
+                self.num_rows(), self.num_columns(), self.map.num_elements(), cells)));
+        }
+        for (i, idx) in self.map.elements_row_major_iter().enumerate() {
+            if *idx >= self.tiles.len() {
+                problems.push(Problem::new(format!("$.map.array[{}]", i),
+                    format!("there's no tile {}, only {} tiles", idx, self.tiles.len())));
+            }
+        }
+        if let Some(phase) = &self.phase {
+            let size = (phase.num_rows(), phase.num_columns());
+            if size != (self.num_rows(), self.num_columns()) || phase.elements_row_major_iter().count() != phase.num_elements() {
+                problems.push(Problem::new("$.phase", format!(
+                    "phase offsets have to cover the map's {} by {} cells", self.num_rows(), self.num_columns())));
+            }
+        }
+        if let Some(meta) = &self.meta {
+            let size = (meta.num_rows(), meta.num_columns());
+            if size != (self.num_rows(), self.num_columns()) || meta.elements_row_major_iter().count() != meta.num_elements() {
+                problems.push(Problem::new("$.meta", format!(
+                    "cell data has to cover the map's {} by {} cells", self.num_rows(), self.num_columns())));
+                return problems;
+            }
+        }
+        if problems.is_empty() {
+            for (i, ((r, c), idx)) in self.map.enumerate_row_major().enumerate() {
+                let target = self.meta(r, c).and_then(|m| m.target);
+                match (target, target.and_then(|to| self.get(to.0, to.1)).map(|t| t.kind)) {
+                    (None, _) if self.tiles[*idx].kind == TileKind::Portal => problems.push(Problem::new(
+                        format!("$.map.array[{}]", i), "this portal doesn't go anywhere; give the cell a target in meta")),
+                    (Some(to), None) => problems.push(Problem::new(
+                        format!("$.meta.array[{}].target", i), format!("{:?} is off the map", to))),
+                    (Some(to), Some(kind @ (TileKind::Wall | TileKind::Pit | TileKind::Door))) => problems.push(Problem::new(
+                        format!("$.meta.array[{}].target", i), format!("{:?} is a {:?}, which can't be stood on", to, kind).to_lowercase())),
+                    _ => {}
+                }
+            }
+        }
+        problems
+    }
+
+}
+
+/// Plain floor, to build test levels out of
+#[cfg(test)]
+pub(crate) fn floor() -> Tile {
+    Tile::from(&Color::GRAY, None)
+}
+
+/// Floor that's some other kind of tile
+#[cfg(test)]
+pub(crate) fn kind(kind: TileKind) -> Tile {
+    Tile { kind, ..floor() }
+}
+
+/// Floor with the goal on it
+#[cfg(test)]
+pub(crate) fn goal() -> Tile {
+    Tile { goal: true, ..floor() }
+}
+
+/// A one-cell-wide level at 120 BPM, with `tiles` going down it from the player at the top
+#[cfg(test)]
+pub(crate) fn column(tiles: impl IntoIterator<Item = Tile>) -> crate::Level {
+    let rows: Vec<Vec<Tile>> = tiles.into_iter().map(|t| vec![t]).collect();
+    crate::Level::new(Array2D::from_rows(&rows).unwrap(), (0, 0), 120.0)
+}
+
+#[test]
+fn phase_offsets(){
+    let pulse = Tile::from(&Color::WHITE, Some(Rhythm::new(4, [0])));
+    let row = Array2D::filled_with(pulse, 1, 4);
+    let mut map = TileMap::from(&row);
+    assert_eq!(map.iter().count(), 1);
+    for c in 0..4 {
+        map.set_phase(0, c, c as Beat);
+    }
+    // one shared tile, but the pulse ripples down the row a beat at a time
+    for beat in 0..8 {
+        let lit: Vec<_> = (0..4).filter(|c| map.get(0, *c).unwrap().on(beat as Beat + 0.5, 0.0).unwrap()).collect();
+        assert_eq!(lit, vec![beat % 4]);
+    }
+    let lit: Vec<_> = map.enumerate_column_major()
+        .filter(|(_, tile)| tile.get_color(1.5) == Color::WHITE)
+        .map(|((_, c), _)| c)
+        .collect();
+    assert_eq!(lit, vec![1]);
+}
+
+#[test]
+fn portals(){
+    let portal = Tile { kind: TileKind::Portal, ..Tile::from(&Color::PURPLE, Some(Rhythm::new(2, [0]))) };
+    let row = Array2D::from_rows(&[vec![portal.clone(), floor(), kind(TileKind::Wall), portal]]).unwrap();
+    let mut map = TileMap::from(&row);
+    // both portals share a tile, so where each goes lives with the cell
+    assert_eq!(map.iter().count(), 3);
+    assert_eq!(map.problems().len(), 2);
+    map.pair_portals((0, 0), (0, 3));
+    assert!(map.problems().is_empty());
+    let mut state = crate::state::LevelState::default();
+    assert_eq!(state.land(&map, (0, 0), (0, 1), 0.0), (0, 3));
+    assert_eq!(state.land(&map, (0, 3), (0, 1), 2.0), (0, 0));
+    // they're shut off the beat
+    assert_eq!(state.land(&map, (0, 3), (0, 1), 1.0), (0, 3));
+    assert_eq!(state.land(&map, (0, 1), (0, 1), 0.0), (0, 1));
+    // and can't lead into walls
+    map.meta_mut(0, 0).unwrap().target = Some((0, 2));
+    assert_eq!(map.problems(), [Problem::new("$.meta.array[0].target", "(0, 2) is a wall, which can't be stood on")]);
+    assert_eq!(state.land(&map, (0, 0), (0, 1), 0.0), (0, 0));
+    map.meta_mut(0, 0).unwrap().target = Some((0, 3));
+
+    let json = serde_json::to_string(&map).unwrap();
+    let loaded: TileMap = serde_json::from_str(&json).unwrap();
+    assert_eq!(loaded.meta(0, 3), Some(&CellMeta { target: Some((0, 0)), ..Default::default() }));
+    assert_eq!(loaded.meta(0, 1), Some(&CellMeta::default()));
+}
@@ -0,0 +1,72 @@
+use crate::rhythm::Sec;
+
+/// Turns uneven frame times into a whole number of equal simulation steps,
+/// so the game plays out the same however fast it's drawn
+#[derive(Debug, Clone, PartialEq)]
+pub struct FixedStep {
+    /// Seconds simulated by each step
+    step: Sec,
+    /// Most steps to run for one frame; past that, time is dropped rather than
+    /// letting a slow frame snowball into slower ones
+    max_steps: u32,
+    /// time handed in but not yet simulated
+    accumulator: Sec,
+}
+
+impl Default for FixedStep {
+    fn default() -> Self {
+        FixedStep::new(FixedStep::STEP, FixedStep::MAX_STEPS)
+    }
+}
+
+impl FixedStep {
+    pub const STEP: Sec = 1.0 / 240.0;
+    pub const MAX_STEPS: u32 = 60;
+
+    pub fn new(step: Sec, max_steps: u32) -> Self {
+        FixedStep { step, max_steps, accumulator: 0.0 }
+    }
+
+    pub fn step(&self) -> Sec {
+        self.step
+    }
+
+    /// Add a frame's `delta` and return how many steps to simulate for it
+    pub fn advance(&mut self, delta: Sec) -> u32 {
+        self.accumulator += delta.max(0.0);
+        let steps = (self.accumulator / self.step).floor();
+        if steps > self.max_steps as Sec {
+            self.accumulator = 0.0;
+            return self.max_steps;
+        }
+        self.accumulator -= steps * self.step;
+        steps as u32
+    }
+
+    /// Time handed in but not simulated yet, always less than a step
+    pub fn pending(&self) -> Sec {
+        self.accumulator
+    }
+
+    /// How far into the next step the present moment is, from 0 to 1, for drawing between steps
+    pub fn alpha(&self) -> f64 {
+        self.accumulator / self.step
+    }
+
+    /// Forget any time not yet simulated
+    pub fn reset(&mut self) {
+        self.accumulator = 0.0;
+    }
+}
+
+#[test]
+fn fixed_steps(){
+    let mut stepper = FixedStep::new(0.25, 4);
+    assert_eq!(stepper.advance(0.1), 0);
+    assert_eq!(stepper.advance(0.3), 1);
+    assert!((stepper.pending() - 0.15).abs() < 1e-9);
+    assert!((stepper.alpha() - 0.6).abs() < 1e-9);
+    // a long stall only catches up so far
+    assert_eq!(stepper.advance(10.0), 4);
+    assert_eq!(stepper.pending(), 0.0);
+}
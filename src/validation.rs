@@ -0,0 +1,54 @@
+use std::fmt;
+
+/// Something wrong with a level file, and where in its JSON it is
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Problem {
+    /// Path to the offending value, like `$.tiles[2].rhythm.length`
+    pub path: String,
+    pub message: String,
+}
+
+impl Problem {
+    pub fn new(path: impl Into<String>, message: impl Into<String>) -> Self {
+        Problem { path: path.into(), message: message.into() }
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.message)
+    }
+}
+
+#[test]
+fn every_problem_is_reported(){
+    use crate::{Level, RCError};
+    let json = r#"{
+        "tiles": [
+            {"color": {"r": 0, "g": 0, "b": 0, "a": 255}, "rhythm": {"length": 0, "beats": []}},
+            {"color": {"r": 0, "g": 0, "b": 0, "a": 255}, "rhythm": null}
+        ],
+        "map": {"array": [0, 1, 2, 1], "num_rows": 2, "num_columns": 2},
+        "starting_location": [2, 0],
+        "tempo": 120
+    }"#;
+    let level: Level = serde_json::from_str(json).unwrap();
+    let Err(RCError::Validation(problems)) = level.validate() else { panic!("the level should be invalid") };
+    let paths: Vec<&str> = problems.iter().map(|p| p.path.as_str()).collect();
+    assert_eq!(paths, ["$.tiles[0].rhythm.length", "$.map.array[2]", "$.starting_location"]);
+    // starting on the cell with no tile doesn't get any further than saying so
+    let level: Level = serde_json::from_str(&json.replace("[2, 0]", "[1, 0]")).unwrap();
+    let Err(RCError::Validation(problems)) = level.validate() else { panic!("the level should be invalid") };
+    assert_eq!(problems.len(), 2);
+
+    // with the grid fixed, the missing goal shows up
+    let level: Level = serde_json::from_str(&json.replace("[0, 1, 2, 1]", "[0, 1, 1, 1]").replace("[2, 0]", "[0, 0]")
+        .replace(r#""length": 0"#, r#""length": 2"#)).unwrap();
+    let Err(RCError::Validation(problems)) = level.validate() else { panic!("the level has no goal") };
+    assert_eq!(problems, [Problem::new("$.tiles", "no tile on the map is a goal")]);
+
+    Level::load("maps/begin.json").unwrap();
+    // bigmap has never had a goal
+    let Err(RCError::Validation(problems)) = Level::load("maps/bigmap.json") else { panic!("bigmap has no goal") };
+    assert_eq!(problems, [Problem::new("$.tiles", "no tile on the map is a goal")]);
+}
@@ -0,0 +1,198 @@
+use raylib::prelude::*;
+use serde::{Serialize, Deserialize};
+use crate::inputs::Direction;
+use crate::rhythm::Beat;
+use crate::tiles::{PlacedTile, TileKind};
+use crate::{Game, PlayerState};
+
+
+#[derive(Debug,Serialize,Deserialize)]
+pub struct TileDimensions {
+    pub tile_width: i32,
+    pub tile_height: i32,
+    pub row_gap: i32,
+    pub column_gap: i32,
+}
+
+impl TileDimensions {
+    pub fn top_left(&self, x: i32, y: i32) -> (i32,i32){
+        (x * (self.tile_width + self.row_gap),
+         y * (self.tile_height + self.column_gap)
+    )
+    }
+
+    pub fn center(&self, x: i32, y: i32) -> (i32, i32) {
+        let (xtl, ytl) = self.top_left(x, y);
+        (xtl + (self.tile_width - self.row_gap) / 2, ytl + (self.tile_height - self.column_gap) / 2)
+    }
+}
+
+
+/// Draws a `Game`. Kept apart from it so the game can run without a window
+pub struct GameView {
+    pub camera: Camera2D,
+    pub dimensions: TileDimensions,
+}
+
+impl GameView {
+    pub fn new(camera: Camera2D, dimensions: TileDimensions) -> Self {
+        Self { camera, dimensions }
+    }
+
+    /// Marks over a tile's color for what it does to the player
+    fn draw_kind(&self, d: &mut impl RaylibDraw, (row, col): (usize, usize), tile: &PlacedTile, beat: Beat) {
+        let (x, y) = self.dimensions.top_left(row as i32, col as i32);
+        let (w, h) = (self.dimensions.tile_width, self.dimensions.tile_height);
+        let (cx, cy) = (x as f32 + w as f32 / 2.0, y as f32 + h as f32 / 2.0);
+        match tile.kind {
+            TileKind::Floor => {},
+            TileKind::Wall => d.draw_rectangle_lines_ex(Rectangle::new(x as f32, y as f32, w as f32, h as f32), 6, Color::DARKGRAY),
+            TileKind::Pit => d.draw_rectangle(x + w / 6, y + h / 6, 2 * w / 3, 2 * h / 3, Color::BLACK),
+            TileKind::Spikes => {
+                if tile.deadly(beat, 0.0) {
+                    let spike = w as f32 / 3.0;
+                    for i in 0..3 {
+                        let left = x as f32 + i as f32 * spike;
+                        triangle(d, Vector2::new(left + spike / 2.0, cy - spike), Vector2::new(left, cy + spike), Vector2::new(left + spike, cy + spike), Color::DARKGRAY);
+                    }
+                }
+            },
+            TileKind::OneWay(direction) => arrow(d, (cx, cy), direction, w.min(h) as f32 / 3.0, Color::DARKGRAY),
+            TileKind::Conveyor(direction) => {
+                // a pair of chevrons that light up on the beats it pushes
+                let color = if tile.push(beat.floor(), None).is_some() { Color::BLACK } else { Color::DARKGRAY };
+                let r = w.min(h) as f32 / 6.0;
+                let (dx, dy) = direction.offset();
+                for along in [-1.0, 1.0] {
+                    arrow(d, (cx + along * dx as f32 * r, cy + along * dy as f32 * r), direction, r, color);
+                }
+            },
+            TileKind::Ice => {
+                for i in 1..4 {
+                    let (ox, oy) = (x + i * w / 4, y + i * h / 4);
+                    d.draw_line(ox, y + h / 8, x + w / 8, oy, Color::WHITE);
+                }
+            },
+            TileKind::Portal => {
+                let open = tile.on(beat, 0.0).unwrap_or(true);
+                let color = if open { Color::RAYWHITE } else { Color::DARKGRAY };
+                for ring in 1..4 {
+                    d.draw_circle_lines(cx as i32, cy as i32, w.min(h) as f32 * ring as f32 / 8.0, color);
+                }
+            },
+            TileKind::Switch => {
+                let (sw, sh) = (w / 3, h / 3);
+                if tile.switched {
+                    d.draw_rectangle(x + sw, y + sh, sw, sh, Color::DARKGRAY);
+                } else {
+                    d.draw_rectangle_lines(x + sw, y + sh, sw, sh, Color::DARKGRAY);
+                }
+            },
+            TileKind::Key if !tile.spent => {
+                d.draw_circle_lines((cx - w as f32 / 8.0) as i32, cy as i32, w as f32 / 8.0, Color::GOLD);
+                d.draw_rectangle(cx as i32, cy as i32 - 2, w / 4, 4, Color::GOLD);
+            },
+            TileKind::Key => {},
+            TileKind::Door if tile.shut() => {
+                for i in 1..4 {
+                    d.draw_rectangle(x + i * w / 4 - 3, y, 6, h, Color::BROWN);
+                }
+            },
+            TileKind::Door => {},
+            TileKind::Crumbling { respawn, .. } if tile.broken() => {
+                // a hole, with the outline of what's coming back if it does
+                d.draw_rectangle(x + w / 6, y + h / 6, 2 * w / 3, 2 * h / 3, Color::BLACK);
+                if respawn.is_some() {
+                    d.draw_rectangle_lines(x, y, w, h, Color::DARKGRAY);
+                }
+            },
+            TileKind::Crumbling { beats, .. } => {
+                // another crack for each beat it's been stood on, spread over six
+                let cracks = (tile.worn * 6).div_ceil(beats);
+                let r = w.min(h) as f32 / 2.0;
+                for i in 0..cracks {
+                    let angle = i as f32 * 2.4 + 0.5;
+                    let (kink, end) = (angle + 0.4, Vector2::new(cx + angle.cos() * r * 0.9, cy + angle.sin() * r * 0.9));
+                    let mid = Vector2::new(cx + kink.cos() * r * 0.45, cy + kink.sin() * r * 0.45);
+                    d.draw_line_v(Vector2::new(cx, cy), mid, Color::BLACK);
+                    d.draw_line_v(mid, end, Color::BLACK);
+                }
+            },
+            TileKind::Spring(direction) => {
+                d.draw_circle_lines(cx as i32, cy as i32, w.min(h) as f32 / 3.0, Color::DARKGRAY);
+                arrow(d, (cx, cy), direction, w.min(h) as f32 / 4.0, Color::DARKGRAY);
+            },
+        }
+    }
+
+    pub fn draw(&self, game: &Game, handle: &mut RaylibDrawHandle){
+        if let (Some(level), Some(player)) = (&game.level, &game.player) {
+            // carry the clock on past the last step, so animation is as smooth as the frame rate
+            let mut clock = game.clock.clone();
+            clock.advance(game.stepper.alpha() * game.stepper.step());
+            {
+            let mut mode2d = handle.begin_mode2D(self.camera);
+                let beat = clock.visual_beat();
+                for ((row,col), tile) in level.cells() {
+                    let (x_tl,y_tl) = self.dimensions.top_left(row as i32, col as i32);
+                    mode2d.draw_rectangle(x_tl,y_tl,
+                        self.dimensions.tile_width,
+                        self.dimensions.tile_height,
+                        tile.get_color(beat)
+                    );
+                    self.draw_kind(&mut mode2d, (row, col), &tile, beat);
+                }
+                let (player_x,player_y)  = self.dimensions.center(
+                    player.position.0 as i32, player.position.1 as i32);
+                let player_radius = self.dimensions.tile_height as f32 * player.size(&clock) / 3.0;
+                mode2d.draw_circle(player_x, player_y, 
+                    player_radius, Color::YELLOW);
+            }
+        
+            let (rows, columns) = level.size_tiles();
+            let height = (rows as i32) * self.dimensions.tile_height;
+            let width = (columns as i32) * self.dimensions.tile_width;
+            let (height, width) = (height as f64, width as f64);
+            let mut draw_msg_box = || {
+                handle.draw_rectangle(
+                    (0.2 * width) as i32, 
+                    (0.2 * height) as i32, 
+                    (0.4 * width) as i32, 
+                    (0.2 * height) as i32, Color::GRAY);
+            };
+            
+            match player.state {
+                PlayerState::Cleared => {
+                    draw_msg_box();
+                    handle.draw_text("Level cleared!", 3 * width as i32/ 10, 
+                    3 * height as i32 / 10, 18, Color::BLACK);
+                    let score = player.score();
+                    let result = format!("Grade {}  {:.1}%  x{}",
+                        score.grade(), 100.0 * score.accuracy(), score.max_combo);
+                    handle.draw_text(&result, 3 * width as i32 / 10, 
+                    3 * height as i32 / 10 + 24, 18, Color::BLACK)
+                },
+                PlayerState::Died => {
+                    draw_msg_box();
+                    handle.draw_text("You died", 3 * width as i32/ 10, 3 * height as i32 / 10, 18, Color::BLACK)
+                }, // need to implement this
+                PlayerState::Playing => {}
+            }
+        }
+    }
+}
+
+/// A triangle pointing `direction`, reaching `r` out from `center`.
+/// Rows run across the screen, so offsets point the same way on it
+fn arrow(d: &mut impl RaylibDraw, (cx, cy): (f32, f32), direction: Direction, r: f32, color: Color) {
+    let (dx, dy) = direction.offset();
+    let (dx, dy) = (dx as f32 * r, dy as f32 * r);
+    triangle(d, Vector2::new(cx + dx, cy + dy), Vector2::new(cx - dx / 2.0 - dy, cy - dy / 2.0 + dx),
+        Vector2::new(cx - dx / 2.0 + dy, cy - dy / 2.0 - dx), color);
+}
+
+/// raylib only fills triangles wound anticlockwise on screen, so wind them that way
+fn triangle(d: &mut impl RaylibDraw, a: Vector2, b: Vector2, c: Vector2, color: Color) {
+    let turn = (b.x - a.x) * (c.y - a.y) - (b.y - a.y) * (c.x - a.x);
+    if turn < 0.0 { d.draw_triangle(a, b, c, color) } else { d.draw_triangle(a, c, b, color) }
+}